@@ -1,96 +1,62 @@
-use async_process::{Command, Stdio};
-use async_stream::stream;
-use futures_lite::{io::BufReader, prelude::*};
-use tokio_stream::Stream;
+//! Controls PulseAudio (or PipeWire through pipewire-pulse).
+//! The default `native` backend talks the native protocol through libpulse, with the `pactl`
+//! feature every call runs `pactl` instead, for systems where libpulse is not available.
+use std::time::Duration;
 
 mod types;
 pub use types::*;
 
-pub struct Pulseaudio {
-    client_name: String,
-}
-impl Pulseaudio {
-    pub fn new(client_name: &str) -> Self {
-        Self {
-            client_name: client_name.to_owned(),
-        }
-    }
-    pub async fn subscribe(&self) -> impl Stream<Item = PulseEvent> {
-        let mut child = Command::new("pactl")
-            .arg("--format")
-            .arg("json")
-            .arg("subscribe")
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-        let mut reader = BufReader::new(child.stdout.take().unwrap());
-        let s = stream! {
-            loop {
-                let mut buf = Vec::new();
-                let _ = reader.read_until("}".as_bytes()[0], &mut buf).await;
-                let s = String::from_utf8(buf).unwrap();
-                let data: serde_json::Value = serde_json::from_str(&s).unwrap();
-                let event_type = if data["event"] == "change" { EventType::Change }
-                    else if data["event"] == "remove" { EventType::Remove }
-                    else {EventType::Unknown};
+#[cfg(feature = "pactl")]
+mod pactl;
+#[cfg(feature = "pactl")]
+pub use pactl::Pulseaudio;
 
-                let target = if data["on"] == "sink" { EventTarget::Sink }
-                    else if data["on"] == "client" { EventTarget::Client }
-                    else { EventTarget::Unknown };
-                let event = PulseEvent {
-                    event_type,
-                    target,
-                };
+#[cfg(all(feature = "native", not(feature = "pactl")))]
+mod native;
+#[cfg(all(feature = "native", not(feature = "pactl")))]
+pub use native::Pulseaudio;
 
+#[cfg(not(any(feature = "native", feature = "pactl")))]
+compile_error!("pulsectl needs a backend, enable the `native` or the `pactl` feature");
 
-            yield event;
+/// the server resolves this to the current default sink
+const DEFAULT_SINK: &str = "@DEFAULT_SINK@";
+/// the server should answer right away, unless it hangs
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Applies the balance to the channels with left or right in their name, like
+/// `pa_cvolume_set_balance`: the louder side gets the maximum, the other one is scaled down.
+fn balance_volumes(channel_map: &str, volumes: &[u32], balance: f64) -> Vec<u32> {
+    let balance = balance.clamp(-1.0, 1.0);
+    let max = channel_map
+        .split(',')
+        .zip(volumes)
+        .filter(|(channel, _)| channel.contains("left") || channel.contains("right"))
+        .map(|(_, v)| *v)
+        .max()
+        .unwrap_or(0);
+    let (left, right) = if balance <= 0.0 {
+        (max, (f64::from(max) * (1.0 + balance)).round() as u32)
+    } else {
+        ((f64::from(max) * (1.0 - balance)).round() as u32, max)
+    };
+    channel_map
+        .split(',')
+        .zip(volumes)
+        .map(|(channel, v)| {
+            if channel.contains("left") {
+                left
+            } else if channel.contains("right") {
+                right
+            } else {
+                *v
             }
-        };
-        s
-    }
-    async fn run_command_with_output<T: serde::de::DeserializeOwned>(
-        &self,
-        command: &str,
-    ) -> Result<T, Error> {
-        let cmd = "--format json ".to_owned() + command;
-        let args = cmd.split_whitespace();
-        let output = async_process::Command::new("pactl")
-            .args(["--client-name", &self.client_name])
-            .args(args)
-            .output()
-            .await
-            .expect("error running pactl");
-        let json_string = String::from_utf8(output.stdout)?;
-        let result = serde_json::from_str(&json_string)?;
-        Ok(result)
-    }
-    async fn run_command(&self, command: &str) -> Result<(), Error> {
-        assert!(!command.is_empty());
-        let args = command.split_whitespace();
-        let output = async_process::Command::new("pactl")
-            .args(["--client-name", &self.client_name])
-            .args(args)
-            .output()
-            .await
-            .expect("error running pactl");
-        if output.status.success() {
-            Ok(())
-        } else {
-            // at the time of writing, the error message is a simple string
-            Err(Error::PulseError(String::from_utf8(output.stdout)?))
-        }
-    }
-    pub async fn set_default_sink(&self, sink: &SinkInfo) -> Result<(), Error> {
-        let cmd = String::from("set-default-sink ") + &sink.name;
-        self.run_command(&cmd).await
-    }
-    pub async fn server_info(&self) -> Result<ServerInfo, Error> {
-        self.run_command_with_output("info").await
-    }
-    pub async fn list_sinks(&self) -> Result<Vec<SinkInfo>, Error> {
-        let sinks: Vec<SinkInfo> = self.run_command_with_output("list sinks").await?;
-        Ok(sinks)
-    }
+        })
+        .collect()
+}
+
+/// shared by both backends
+impl Pulseaudio {
     pub async fn find_sink_by_name(&self, name: &str) -> Option<SinkInfo> {
         let sinks = self.list_sinks().await.ok()?;
         for s in sinks {
@@ -100,52 +66,77 @@ impl Pulseaudio {
         }
         None
     }
-    pub async fn get_default_sink(&self) -> Result<SinkInfo, Error> {
-        let default_sink_name = self.server_info().await?.default_sink_name;
-        let sinks = self.list_sinks().await?;
-        let default_sink = sinks
-            .into_iter()
-            .filter(|s| s.name == default_sink_name)
-            .last()
-            .ok_or(Error::PulseError("could not get default sink".to_owned()));
-        default_sink
+    pub async fn find_source_by_name(&self, name: &str) -> Option<SourceInfo> {
+        let sources = self.list_sources().await.ok()?;
+        sources.into_iter().find(|s| s.name == name)
     }
-    // pactl actually has an easier command for this:
-    // `pactl --format json get-sink-volume @DEFAULT_SINK@`
-    // but that doesn't output json as of pactl v16.1 so it's annoying to parse
-    // TODO: check back for future versions
-    pub async fn get_default_volume(&self) -> Result<Volume, Error> {
-        let default_sink = self.get_default_sink().await?;
-        let volume = default_sink
-            .volume
-            .get("front-left")
-            .ok_or(Error::PulseError("channel does not exist".to_owned()))?;
-        Ok(volume.clone())
+    pub async fn find_card_by_name(&self, name: &str) -> Option<CardInfo> {
+        let cards = self.list_cards().await.ok()?;
+        cards.into_iter().find(|c| c.name == name)
     }
-    pub async fn volume_up(&self, step: u8) -> Result<(), Error> {
-        self.run_command(&("set-sink-volume @DEFAULT_SINK@ +".to_owned() + &step.to_string() + "%"))
+    /// `name` can be `@DEFAULT_SINK@`
+    pub async fn get_sink(&self, name: &str) -> Result<SinkInfo, Error> {
+        if name == DEFAULT_SINK {
+            return self.get_default_sink().await;
+        }
+        self.find_sink_by_name(name)
             .await
+            .ok_or_else(|| Error::PulseError(format!("sink {name} does not exist")))
     }
-    pub async fn volume_down(&self, step: u8) -> Result<(), Error> {
-        self.run_command(&("set-sink-volume @DEFAULT_SINK@ -".to_owned() + &step.to_string() + "%"))
-            .await
+    /// the loudest channel of the default sink, so the balance doesn't change it
+    pub async fn get_default_volume(&self) -> Result<Volume, Error> {
+        Ok(self.get_default_sink().await?.max_volume())
     }
-    pub async fn toggle_mute(&self) -> Result<(), Error> {
-        self.run_command("set-sink-mute @DEFAULT_SINK@ toggle")
-            .await
+    /// sets all channels of the sink to the same volume
+    pub async fn set_sink_volume(&self, name: &str, volume: VolumeLevel) -> Result<(), Error> {
+        let sink = self.get_sink(name).await?;
+        let volumes = vec![volume.to_raw(); sink.channel_volumes().len()];
+        self.set_sink_channel_volumes(&sink.name, &volumes).await
+    }
+    /// sets one channel of the sink, e.g. `front-left`, the others keep their volume
+    pub async fn set_sink_channel_volume(
+        &self,
+        name: &str,
+        channel: &str,
+        volume: VolumeLevel,
+    ) -> Result<(), Error> {
+        let sink = self.get_sink(name).await?;
+        let mut volumes = sink.channel_volumes();
+        let position = sink
+            .channel_map
+            .split(',')
+            .position(|c| c == channel)
+            .ok_or_else(|| Error::PulseError(format!("channel {channel} does not exist")))?;
+        volumes[position] = volume.to_raw();
+        self.set_sink_channel_volumes(&sink.name, &volumes).await
+    }
+    /// -1.0 is left only, 0.0 centered and 1.0 right only, the loudest channel keeps its volume
+    pub async fn set_balance(&self, name: &str, balance: f64) -> Result<(), Error> {
+        let sink = self.get_sink(name).await?;
+        let volumes = balance_volumes(&sink.channel_map, &sink.channel_volumes(), balance);
+        self.set_sink_channel_volumes(&sink.name, &volumes).await
     }
     pub async fn cycle_sinks(&self) -> Result<(), Error> {
-        let sinks = self.list_sinks().await?;
+        self.cycle_sinks_where(|_| true).await
+    }
+    /// switches to the next sink that passes the filter
+    pub async fn cycle_sinks_where(&self, filter: impl Fn(&SinkInfo) -> bool) -> Result<(), Error> {
+        let sinks: Vec<SinkInfo> = self
+            .list_sinks()
+            .await?
+            .into_iter()
+            .filter(|s| filter(s))
+            .collect();
         let default_sink_name = self.server_info().await?.default_sink_name;
-        let sinks_count = sinks.len();
-        for (i, sink) in sinks.iter().enumerate() {
-            if sink.name == default_sink_name {
-                let new_sink = sinks.get((i + 1) % sinks_count).expect("error lmao");
-                self.set_default_sink(&new_sink).await?;
-                return Ok(());
-            }
-        }
-        Err(Error::PulseError("Couldn't cycle sinks".to_owned()))
+        // starts with the first sink if the current one is filtered out
+        let next = match sinks.iter().position(|s| s.name == default_sink_name) {
+            Some(i) => sinks.get((i + 1) % sinks.len()),
+            None => sinks.first(),
+        };
+        let Some(next) = next else {
+            return Err(Error::PulseError("Couldn't cycle sinks".to_owned()));
+        };
+        self.set_default_sink(next).await
     }
 }
 
@@ -153,6 +144,7 @@ impl Pulseaudio {
 mod test {
     use std::time::Duration;
 
+    use futures_util::{pin_mut, StreamExt};
     use tokio_test::block_on;
 
     use super::*;
@@ -160,19 +152,30 @@ mod test {
     const TEST_CLIENT_NAME: &str = "test-client";
 
     #[test]
-    fn run_error_cmd() {
-        let pulse = Pulseaudio::new(TEST_CLIENT_NAME);
-        {
-            let result = block_on(pulse.run_command("blub"));
-            let error = result.err().unwrap();
-            assert!(matches!(error, Error::PulseError(_)));
-        }
-        {
-            let result = block_on(pulse.run_command("list abcde"));
-            let error = result.err().unwrap();
-            assert!(matches!(error, Error::PulseError(_)));
-        }
+    fn balance_keeps_the_loudest_channel() {
+        let channels = "front-left,front-right,lfe";
+        assert_eq!(
+            balance_volumes(channels, &[1000, 800, 500], 0.0),
+            vec![1000, 1000, 500]
+        );
+        assert_eq!(
+            balance_volumes(channels, &[1000, 800, 500], -0.5),
+            vec![1000, 500, 500]
+        );
+        assert_eq!(
+            balance_volumes(channels, &[1000, 800, 500], 2.0),
+            vec![0, 1000, 500]
+        );
     }
+
+    #[test]
+    fn volume_levels() {
+        assert_eq!(VolumeLevel::Percent(100.0).to_raw(), VOLUME_NORM);
+        assert_eq!(VolumeLevel::Db(0.0).to_raw(), VOLUME_NORM);
+        assert_eq!(VolumeLevel::Percent(-5.0).to_raw(), 0);
+        assert_eq!(Volume::from_raw(VOLUME_NORM / 2).value_percent, "50%");
+    }
+
     #[test]
     fn list_sinks() {
         let pulse = Pulseaudio::new(TEST_CLIENT_NAME);
@@ -180,6 +183,14 @@ mod test {
         assert!(sinks.len() > 0);
     }
     #[test]
+    fn sink_inputs_play_on_existing_sinks() {
+        let pulse = Pulseaudio::new(TEST_CLIENT_NAME);
+        let sinks = block_on(pulse.list_sinks()).unwrap();
+        for input in block_on(pulse.list_sink_inputs()).unwrap() {
+            assert!(sinks.iter().any(|s| s.index == input.sink));
+        }
+    }
+    #[test]
     fn get_default_volume_string() {
         let pulse = Pulseaudio::new(TEST_CLIENT_NAME);
         let volume = block_on(pulse.get_default_volume()).unwrap().value_percent;
@@ -218,7 +229,7 @@ mod test {
     #[serial_test::serial]
     fn subscribe() {
         let pulse = Pulseaudio::new(TEST_CLIENT_NAME);
-        let stream = block_on(pulse.subscribe());
+        let stream = block_on(pulse.subscribe()).unwrap();
         pin_mut!(stream);
         while let Some(s) = block_on(stream.next()) {
             dbg!(s);
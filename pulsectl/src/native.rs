@@ -0,0 +1,917 @@
+//! Backend that talks the native protocol through libpulse.
+//!
+//! libpulse objects can't leave the thread they were created on, so every `Pulseaudio` owns a
+//! worker thread with the connection and sends it requests. The threaded mainloop calls the
+//! callbacks on its own thread, they send the results back through oneshot channels.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+
+use async_io::Timer;
+use futures_channel::{mpsc as async_mpsc, oneshot};
+use futures_lite::future;
+use libpulse_binding as pulse;
+use pulse::callbacks::ListResult;
+use pulse::context::introspect;
+use pulse::context::subscribe::{Facility, InterestMaskSet, Operation};
+use pulse::context::{Context, FlagSet as ContextFlagSet, State};
+use pulse::def::{SinkState, SourceState};
+use pulse::mainloop::threaded::Mainloop;
+use pulse::proplist::Proplist;
+use pulse::volume::{ChannelVolumes, Volume as PaVolume};
+use tokio_stream::Stream;
+
+use crate::{
+    CardInfo, CardProfile, Error, EventTarget, EventType, PulseEvent, ServerInfo, SinkInfo,
+    SinkInputInfo, SourceInfo, SourceOutputInfo, Volume, COMMAND_TIMEOUT, DEFAULT_SINK,
+};
+
+const DEFAULT_SOURCE: &str = "@DEFAULT_SOURCE@";
+
+type Reply<T> = oneshot::Sender<Result<T, Error>>;
+type Subscriber = async_mpsc::UnboundedSender<Result<PulseEvent, Error>>;
+
+enum Request {
+    ServerInfo(Reply<ServerInfo>),
+    ListSinks(Reply<Vec<SinkInfo>>),
+    GetSink {
+        name: String,
+        reply: Reply<SinkInfo>,
+    },
+    SetDefaultSink {
+        name: String,
+        reply: Reply<()>,
+    },
+    /// raw volume per channel, in the order of the sink's channel map
+    SetSinkVolume {
+        name: String,
+        volume: Vec<u32>,
+        reply: Reply<()>,
+    },
+    SetSinkMute {
+        name: String,
+        mute: bool,
+        reply: Reply<()>,
+    },
+    ListSources(Reply<Vec<SourceInfo>>),
+    GetSource {
+        name: String,
+        reply: Reply<SourceInfo>,
+    },
+    SetDefaultSource {
+        name: String,
+        reply: Reply<()>,
+    },
+    /// raw volume per channel, in the order of the source's channel map
+    SetSourceVolume {
+        name: String,
+        volume: Vec<u32>,
+        reply: Reply<()>,
+    },
+    SetSourceMute {
+        name: String,
+        mute: bool,
+        reply: Reply<()>,
+    },
+    ListSinkInputs(Reply<Vec<SinkInputInfo>>),
+    GetSinkInput {
+        index: u32,
+        reply: Reply<SinkInputInfo>,
+    },
+    /// raw volume per channel, in the order of the stream's channel map
+    SetSinkInputVolume {
+        index: u32,
+        volume: Vec<u32>,
+        reply: Reply<()>,
+    },
+    SetSinkInputMute {
+        index: u32,
+        mute: bool,
+        reply: Reply<()>,
+    },
+    MoveSinkInput {
+        index: u32,
+        sink_name: String,
+        reply: Reply<()>,
+    },
+    ListSourceOutputs(Reply<Vec<SourceOutputInfo>>),
+    ListCards(Reply<Vec<CardInfo>>),
+    SetCardProfile {
+        name: String,
+        profile: String,
+        reply: Reply<()>,
+    },
+    Subscribe(Subscriber),
+}
+
+impl Request {
+    fn fail(self, error: Error) {
+        match self {
+            Request::ServerInfo(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Request::ListSinks(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Request::GetSink { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Request::ListSources(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Request::GetSource { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Request::ListSinkInputs(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Request::GetSinkInput { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Request::ListSourceOutputs(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Request::ListCards(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Request::SetDefaultSink { reply, .. }
+            | Request::SetSinkVolume { reply, .. }
+            | Request::SetSinkMute { reply, .. }
+            | Request::SetDefaultSource { reply, .. }
+            | Request::SetSourceVolume { reply, .. }
+            | Request::SetSourceMute { reply, .. }
+            | Request::SetSinkInputVolume { reply, .. }
+            | Request::SetSinkInputMute { reply, .. }
+            | Request::MoveSinkInput { reply, .. }
+            | Request::SetCardProfile { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            // dropping the sender ends the stream
+            Request::Subscribe(_) => {}
+        }
+    }
+}
+
+/// List callbacks are called once per item and once more at the end,
+/// but the reply can only be sent once.
+struct OnceReply<T>(Option<Reply<T>>);
+
+impl<T> OnceReply<T> {
+    fn send(&mut self, result: Result<T, Error>) {
+        if let Some(reply) = self.0.take() {
+            let _ = reply.send(result);
+        }
+    }
+}
+
+fn success_reply(reply: Reply<()>, action: &'static str) -> impl FnMut(bool) + 'static {
+    let mut reply = OnceReply(Some(reply));
+    move |success| {
+        reply.send(if success {
+            Ok(())
+        } else {
+            Err(Error::PulseError(format!("could not {action}")))
+        })
+    }
+}
+
+fn sink_state_to_string(state: SinkState) -> String {
+    match state {
+        SinkState::Running => "RUNNING",
+        SinkState::Idle => "IDLE",
+        SinkState::Suspended => "SUSPENDED",
+        SinkState::Invalid => "INVALID",
+    }
+    .to_owned()
+}
+
+fn source_state_to_string(state: SourceState) -> String {
+    match state {
+        SourceState::Running => "RUNNING",
+        SourceState::Idle => "IDLE",
+        SourceState::Suspended => "SUSPENDED",
+        SourceState::Invalid => "INVALID",
+    }
+    .to_owned()
+}
+
+/// channel name -> volume, like in the pactl output
+fn channel_volumes(channel_map: &str, volumes: &ChannelVolumes) -> BTreeMap<String, Volume> {
+    channel_map
+        .split(',')
+        .zip(volumes.get())
+        .map(|(channel, volume)| (channel.to_owned(), Volume::from_raw(volume.0)))
+        .collect()
+}
+
+fn channel_volumes_to_pa(volume: Vec<u32>) -> ChannelVolumes {
+    let mut volumes = ChannelVolumes::default();
+    volumes.set_len(volume.len() as u8);
+    for (v, value) in volumes.get_mut().iter_mut().zip(volume) {
+        *v = PaVolume(value);
+    }
+    volumes
+}
+
+fn sink_from_pa(info: &introspect::SinkInfo) -> SinkInfo {
+    let channel_map = info.channel_map.print();
+    let volume = channel_volumes(&channel_map, &info.volume);
+    SinkInfo {
+        index: info.index,
+        state: sink_state_to_string(info.state),
+        name: info.name.as_deref().unwrap_or_default().to_owned(),
+        description: info.description.as_deref().unwrap_or_default().to_owned(),
+        mute: info.mute,
+        channel_map,
+        volume,
+        properties: properties_from_pa(&info.proplist),
+    }
+}
+
+fn source_from_pa(info: &introspect::SourceInfo) -> SourceInfo {
+    let channel_map = info.channel_map.print();
+    let volume = channel_volumes(&channel_map, &info.volume);
+    SourceInfo {
+        index: info.index,
+        state: source_state_to_string(info.state),
+        name: info.name.as_deref().unwrap_or_default().to_owned(),
+        mute: info.mute,
+        channel_map,
+        volume,
+        monitor_of_sink: info
+            .monitor_of_sink_name
+            .as_deref()
+            .unwrap_or("n/a")
+            .to_owned(),
+    }
+}
+
+fn sink_input_from_pa(info: &introspect::SinkInputInfo) -> SinkInputInfo {
+    let channel_map = info.channel_map.print();
+    let volume = channel_volumes(&channel_map, &info.volume);
+    SinkInputInfo {
+        index: info.index,
+        sink: info.sink,
+        mute: info.mute,
+        corked: info.corked,
+        channel_map,
+        volume,
+        properties: properties_from_pa(&info.proplist),
+    }
+}
+
+fn source_output_from_pa(info: &introspect::SourceOutputInfo) -> SourceOutputInfo {
+    SourceOutputInfo {
+        index: info.index,
+        source: info.source,
+        mute: info.mute,
+        corked: info.corked,
+        properties: properties_from_pa(&info.proplist),
+    }
+}
+
+fn properties_from_pa(proplist: &Proplist) -> BTreeMap<String, String> {
+    proplist
+        .iter()
+        .filter_map(|key| {
+            let value = proplist.get_str(&key)?;
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn card_from_pa(info: &introspect::CardInfo) -> CardInfo {
+    let profiles = info
+        .profiles
+        .iter()
+        .filter_map(|profile| {
+            let name = profile.name.as_deref()?.to_owned();
+            let profile = CardProfile {
+                description: profile
+                    .description
+                    .as_deref()
+                    .unwrap_or_default()
+                    .to_owned(),
+                sinks: profile.n_sinks,
+                sources: profile.n_sources,
+                priority: profile.priority,
+                available: profile.available,
+            };
+            Some((name, profile))
+        })
+        .collect();
+    CardInfo {
+        index: info.index,
+        name: info.name.as_deref().unwrap_or_default().to_owned(),
+        profiles,
+        active_profile: info
+            .active_profile
+            .as_ref()
+            .and_then(|profile| profile.name.as_deref())
+            .map(str::to_owned),
+        properties: properties_from_pa(&info.proplist),
+    }
+}
+
+fn event_from_pa(
+    facility: Option<Facility>,
+    operation: Option<Operation>,
+    index: u32,
+) -> PulseEvent {
+    let event_type = match operation {
+        Some(Operation::New) => EventType::New,
+        Some(Operation::Changed) => EventType::Change,
+        Some(Operation::Removed) => EventType::Remove,
+        _ => EventType::Unknown,
+    };
+    let target = match facility {
+        Some(Facility::Sink) => EventTarget::Sink,
+        Some(Facility::Source) => EventTarget::Source,
+        Some(Facility::SinkInput) => EventTarget::SinkInput,
+        Some(Facility::SourceOutput) => EventTarget::SourceOutput,
+        Some(Facility::Card) => EventTarget::Card,
+        Some(Facility::Server) => EventTarget::Server,
+        Some(Facility::Client) => EventTarget::Client,
+        _ => EventTarget::Unknown,
+    };
+    PulseEvent {
+        event_type,
+        target,
+        index,
+    }
+}
+
+/// connection to the server, only used on the worker thread
+struct Connection {
+    // dropped before the mainloop
+    context: Context,
+    mainloop: Mainloop,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Connection {
+    fn connect(client_name: &str) -> Result<Self, Error> {
+        let mainloop =
+            Rc::new(RefCell::new(Mainloop::new().ok_or_else(|| {
+                Error::PulseError("could not create mainloop".to_owned())
+            })?));
+        let mut context = Context::new(&*mainloop.borrow(), client_name)
+            .ok_or_else(|| Error::PulseError("could not create context".to_owned()))?;
+        // wakes up the wait below whenever the state changes
+        let mainloop_state = mainloop.clone();
+        context.set_state_callback(Some(Box::new(move || {
+            // the waiting thread holds the borrow but released the lock, signal only needs the
+            // pointer, like the threaded mainloop example of libpulse-binding does
+            unsafe { (*mainloop_state.as_ptr()).signal(false) };
+        })));
+        context
+            .connect(None, ContextFlagSet::NOAUTOSPAWN, None)
+            .map_err(|e| Error::PulseError(format!("could not connect: {e:?}")))?;
+
+        let mut mainloop_guard = mainloop.borrow_mut();
+        mainloop_guard.lock();
+        if let Err(e) = mainloop_guard.start() {
+            mainloop_guard.unlock();
+            return Err(Error::PulseError(format!(
+                "could not start mainloop: {e:?}"
+            )));
+        }
+        // a hanging server blocks the worker here, the requests waiting for it time out anyway
+        loop {
+            match context.get_state() {
+                State::Ready => break,
+                State::Failed | State::Terminated => {
+                    mainloop_guard.unlock();
+                    mainloop_guard.stop();
+                    return Err(Error::PulseError("connection refused".to_owned()));
+                }
+                _ => mainloop_guard.wait(),
+            }
+        }
+
+        let subscribers: Arc<Mutex<Vec<Subscriber>>> = Arc::default();
+        let subscribers_event = subscribers.clone();
+        context.set_subscribe_callback(Some(Box::new(move |facility, operation, index| {
+            let event = event_from_pa(facility, operation, index);
+            let mut subscribers = subscribers_event.lock().unwrap();
+            subscribers.retain(|s| s.unbounded_send(Ok(event.clone())).is_ok());
+        })));
+        context.subscribe(InterestMaskSet::ALL, |_| {});
+        // ready only ever changes to failed or terminated, the subscriptions end with it
+        let subscribers_state = subscribers.clone();
+        context.set_state_callback(Some(Box::new(move || {
+            for subscriber in subscribers_state.lock().unwrap().drain(..) {
+                let _ = subscriber.unbounded_send(Err(Error::PulseError(
+                    "lost the connection to the server".to_owned(),
+                )));
+            }
+        })));
+        mainloop_guard.unlock();
+        drop(mainloop_guard);
+
+        // replacing the state callback dropped the last other reference
+        let mainloop = Rc::try_unwrap(mainloop)
+            .map_err(|_| Error::PulseError("mainloop is still in use".to_owned()))?
+            .into_inner();
+        Ok(Self {
+            context,
+            mainloop,
+            subscribers,
+        })
+    }
+
+    fn is_ready(&mut self) -> bool {
+        self.mainloop.lock();
+        let ready = self.context.get_state() == State::Ready;
+        self.mainloop.unlock();
+        ready
+    }
+
+    fn handle(&mut self, request: Request) {
+        self.mainloop.lock();
+        match request {
+            Request::ServerInfo(reply) => {
+                let server_string = self.context.get_server().unwrap_or_default();
+                let mut reply = OnceReply(Some(reply));
+                self.context.introspect().get_server_info(move |info| {
+                    reply.send(Ok(ServerInfo {
+                        server_string: server_string.clone(),
+                        default_sink_name: info
+                            .default_sink_name
+                            .as_deref()
+                            .unwrap_or_default()
+                            .to_owned(),
+                        default_source_name: info
+                            .default_source_name
+                            .as_deref()
+                            .unwrap_or_default()
+                            .to_owned(),
+                    }))
+                });
+            }
+            Request::ListSinks(reply) => {
+                let mut reply = OnceReply(Some(reply));
+                let mut sinks = Vec::new();
+                self.context
+                    .introspect()
+                    .get_sink_info_list(move |result| match result {
+                        ListResult::Item(info) => sinks.push(sink_from_pa(info)),
+                        ListResult::End => reply.send(Ok(std::mem::take(&mut sinks))),
+                        ListResult::Error => {
+                            reply.send(Err(Error::PulseError("could not list sinks".to_owned())))
+                        }
+                    });
+            }
+            Request::GetSink { name, reply } => {
+                let mut reply = OnceReply(Some(reply));
+                let mut sink = None;
+                self.context
+                    .introspect()
+                    .get_sink_info_by_name(&name.clone(), move |result| match result {
+                        ListResult::Item(info) => sink = Some(sink_from_pa(info)),
+                        ListResult::End => reply.send(sink.take().ok_or_else(|| {
+                            Error::PulseError(format!("sink {name} does not exist"))
+                        })),
+                        ListResult::Error => {
+                            reply.send(Err(Error::PulseError(format!("could not get sink {name}"))))
+                        }
+                    });
+            }
+            Request::SetDefaultSink { name, reply } => {
+                self.context
+                    .set_default_sink(&name, success_reply(reply, "set default sink"));
+            }
+            Request::SetSinkVolume {
+                name,
+                volume,
+                reply,
+            } => {
+                self.context.introspect().set_sink_volume_by_name(
+                    &name,
+                    &channel_volumes_to_pa(volume),
+                    Some(Box::new(success_reply(reply, "set sink volume"))),
+                );
+            }
+            Request::SetSinkMute { name, mute, reply } => {
+                self.context.introspect().set_sink_mute_by_name(
+                    &name,
+                    mute,
+                    Some(Box::new(success_reply(reply, "set sink mute"))),
+                );
+            }
+            Request::ListSources(reply) => {
+                let mut reply = OnceReply(Some(reply));
+                let mut sources = Vec::new();
+                self.context
+                    .introspect()
+                    .get_source_info_list(move |result| match result {
+                        ListResult::Item(info) => sources.push(source_from_pa(info)),
+                        ListResult::End => reply.send(Ok(std::mem::take(&mut sources))),
+                        ListResult::Error => {
+                            reply.send(Err(Error::PulseError("could not list sources".to_owned())))
+                        }
+                    });
+            }
+            Request::GetSource { name, reply } => {
+                let mut reply = OnceReply(Some(reply));
+                let mut source = None;
+                self.context
+                    .introspect()
+                    .get_source_info_by_name(&name.clone(), move |result| match result {
+                        ListResult::Item(info) => source = Some(source_from_pa(info)),
+                        ListResult::End => reply.send(source.take().ok_or_else(|| {
+                            Error::PulseError(format!("source {name} does not exist"))
+                        })),
+                        ListResult::Error => reply.send(Err(Error::PulseError(format!(
+                            "could not get source {name}"
+                        )))),
+                    });
+            }
+            Request::SetDefaultSource { name, reply } => {
+                self.context
+                    .set_default_source(&name, success_reply(reply, "set default source"));
+            }
+            Request::SetSourceVolume {
+                name,
+                volume,
+                reply,
+            } => {
+                self.context.introspect().set_source_volume_by_name(
+                    &name,
+                    &channel_volumes_to_pa(volume),
+                    Some(Box::new(success_reply(reply, "set source volume"))),
+                );
+            }
+            Request::SetSourceMute { name, mute, reply } => {
+                self.context.introspect().set_source_mute_by_name(
+                    &name,
+                    mute,
+                    Some(Box::new(success_reply(reply, "set source mute"))),
+                );
+            }
+            Request::ListSinkInputs(reply) => {
+                let mut reply = OnceReply(Some(reply));
+                let mut inputs = Vec::new();
+                self.context
+                    .introspect()
+                    .get_sink_input_info_list(move |result| match result {
+                        ListResult::Item(info) => inputs.push(sink_input_from_pa(info)),
+                        ListResult::End => reply.send(Ok(std::mem::take(&mut inputs))),
+                        ListResult::Error => reply.send(Err(Error::PulseError(
+                            "could not list sink inputs".to_owned(),
+                        ))),
+                    });
+            }
+            Request::GetSinkInput { index, reply } => {
+                let mut reply = OnceReply(Some(reply));
+                let mut input = None;
+                self.context
+                    .introspect()
+                    .get_sink_input_info(index, move |result| match result {
+                        ListResult::Item(info) => input = Some(sink_input_from_pa(info)),
+                        ListResult::End => reply.send(input.take().ok_or_else(|| {
+                            Error::PulseError(format!("sink input {index} does not exist"))
+                        })),
+                        ListResult::Error => reply.send(Err(Error::PulseError(format!(
+                            "could not get sink input {index}"
+                        )))),
+                    });
+            }
+            Request::SetSinkInputVolume {
+                index,
+                volume,
+                reply,
+            } => {
+                self.context.introspect().set_sink_input_volume(
+                    index,
+                    &channel_volumes_to_pa(volume),
+                    Some(Box::new(success_reply(reply, "set sink input volume"))),
+                );
+            }
+            Request::SetSinkInputMute { index, mute, reply } => {
+                self.context.introspect().set_sink_input_mute(
+                    index,
+                    mute,
+                    Some(Box::new(success_reply(reply, "set sink input mute"))),
+                );
+            }
+            Request::MoveSinkInput {
+                index,
+                sink_name,
+                reply,
+            } => {
+                self.context.introspect().move_sink_input_by_name(
+                    index,
+                    &sink_name,
+                    Some(Box::new(success_reply(reply, "move sink input"))),
+                );
+            }
+            Request::ListSourceOutputs(reply) => {
+                let mut reply = OnceReply(Some(reply));
+                let mut outputs = Vec::new();
+                self.context
+                    .introspect()
+                    .get_source_output_info_list(move |result| match result {
+                        ListResult::Item(info) => outputs.push(source_output_from_pa(info)),
+                        ListResult::End => reply.send(Ok(std::mem::take(&mut outputs))),
+                        ListResult::Error => reply.send(Err(Error::PulseError(
+                            "could not list source outputs".to_owned(),
+                        ))),
+                    });
+            }
+            Request::ListCards(reply) => {
+                let mut reply = OnceReply(Some(reply));
+                let mut cards = Vec::new();
+                self.context
+                    .introspect()
+                    .get_card_info_list(move |result| match result {
+                        ListResult::Item(info) => cards.push(card_from_pa(info)),
+                        ListResult::End => reply.send(Ok(std::mem::take(&mut cards))),
+                        ListResult::Error => {
+                            reply.send(Err(Error::PulseError("could not list cards".to_owned())))
+                        }
+                    });
+            }
+            Request::SetCardProfile {
+                name,
+                profile,
+                reply,
+            } => {
+                self.context.introspect().set_card_profile_by_name(
+                    &name,
+                    &profile,
+                    Some(Box::new(success_reply(reply, "set card profile"))),
+                );
+            }
+            Request::Subscribe(subscriber) => {
+                self.subscribers.lock().unwrap().push(subscriber);
+            }
+        }
+        self.mainloop.unlock();
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.mainloop.lock();
+        self.context.disconnect();
+        self.mainloop.unlock();
+        self.mainloop.stop();
+    }
+}
+
+// (re)connects on demand, so a restarted server is picked up by the next request
+fn worker(client_name: String, requests: mpsc::Receiver<Request>) {
+    let mut connection: Option<Connection> = None;
+    while let Ok(request) = requests.recv() {
+        if !connection.as_mut().is_some_and(Connection::is_ready) {
+            connection = None;
+            match Connection::connect(&client_name) {
+                Ok(c) => connection = Some(c),
+                Err(e) => {
+                    request.fail(e);
+                    continue;
+                }
+            }
+        }
+        if let Some(connection) = &mut connection {
+            connection.handle(request);
+        }
+    }
+}
+
+pub struct Pulseaudio {
+    requests: Mutex<mpsc::Sender<Request>>,
+}
+impl Pulseaudio {
+    pub fn new(client_name: &str) -> Self {
+        let (requests, receiver) = mpsc::channel();
+        let client_name = client_name.to_owned();
+        std::thread::spawn(move || worker(client_name, receiver));
+        Self {
+            requests: Mutex::new(requests),
+        }
+    }
+    async fn request<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> Result<T, Error> {
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .lock()
+            .unwrap()
+            .send(request(reply))
+            .map_err(|_| Error::PulseError("pulseaudio thread stopped".to_owned()))?;
+        // the worker might hang in libpulse or stop before it replies
+        let reply = async {
+            result
+                .await
+                .map_err(|_| Error::PulseError("pulseaudio request was dropped".to_owned()))
+        };
+        let timeout = async {
+            Timer::after(COMMAND_TIMEOUT).await;
+            Err(Error::Timeout)
+        };
+        future::or(reply, timeout).await?
+    }
+    /// The stream ends with an error when the connection to the server is lost.
+    pub async fn subscribe(&self) -> Result<impl Stream<Item = Result<PulseEvent, Error>>, Error> {
+        // make sure there is a connection, otherwise the stream would end right away
+        self.server_info().await?;
+        let (events, stream) = async_mpsc::unbounded();
+        self.requests
+            .lock()
+            .unwrap()
+            .send(Request::Subscribe(events))
+            .map_err(|_| Error::PulseError("pulseaudio thread stopped".to_owned()))?;
+        Ok(stream)
+    }
+    pub async fn set_default_sink(&self, sink: &SinkInfo) -> Result<(), Error> {
+        let name = sink.name.clone();
+        self.request(|reply| Request::SetDefaultSink { name, reply })
+            .await
+    }
+    pub async fn server_info(&self) -> Result<ServerInfo, Error> {
+        self.request(Request::ServerInfo).await
+    }
+    pub async fn list_sinks(&self) -> Result<Vec<SinkInfo>, Error> {
+        self.request(Request::ListSinks).await
+    }
+    pub async fn get_default_sink(&self) -> Result<SinkInfo, Error> {
+        let name = DEFAULT_SINK.to_owned();
+        self.request(|reply| Request::GetSink { name, reply }).await
+    }
+    /// sets the volume of every channel of the default sink to `f(current volume)`
+    async fn update_volume(&self, f: impl Fn(u32) -> u32) -> Result<(), Error> {
+        let sink = self.get_default_sink().await?;
+        let volume = sink
+            .channel_map
+            .split(',')
+            .filter_map(|channel| sink.volume.get(channel))
+            .map(|v| f(v.value).min(PaVolume::MAX.0))
+            .collect();
+        let name = sink.name;
+        self.request(|reply| Request::SetSinkVolume {
+            name,
+            volume,
+            reply,
+        })
+        .await
+    }
+    pub async fn volume_up(&self, step: u8) -> Result<(), Error> {
+        let step = PaVolume::NORMAL.0 * u32::from(step) / 100;
+        self.update_volume(|v| v.saturating_add(step)).await
+    }
+    pub async fn volume_down(&self, step: u8) -> Result<(), Error> {
+        let step = PaVolume::NORMAL.0 * u32::from(step) / 100;
+        self.update_volume(|v| v.saturating_sub(step)).await
+    }
+    pub async fn set_volume(&self, percent: u8) -> Result<(), Error> {
+        let volume = PaVolume::NORMAL.0 * u32::from(percent) / 100;
+        self.update_volume(|_| volume).await
+    }
+    /// raw volumes in the order of the sink's channel map
+    pub async fn set_sink_channel_volumes(&self, name: &str, volumes: &[u32]) -> Result<(), Error> {
+        let name = name.to_owned();
+        let volume = volumes.iter().map(|v| (*v).min(PaVolume::MAX.0)).collect();
+        self.request(|reply| Request::SetSinkVolume {
+            name,
+            volume,
+            reply,
+        })
+        .await
+    }
+    pub async fn set_mute(&self, mute: bool) -> Result<(), Error> {
+        let name = DEFAULT_SINK.to_owned();
+        self.request(|reply| Request::SetSinkMute { name, mute, reply })
+            .await
+    }
+    pub async fn toggle_mute(&self) -> Result<(), Error> {
+        let sink = self.get_default_sink().await?;
+        self.set_mute(!sink.mute).await
+    }
+    pub async fn list_sources(&self) -> Result<Vec<SourceInfo>, Error> {
+        self.request(Request::ListSources).await
+    }
+    pub async fn get_default_source(&self) -> Result<SourceInfo, Error> {
+        let name = DEFAULT_SOURCE.to_owned();
+        self.request(|reply| Request::GetSource { name, reply })
+            .await
+    }
+    pub async fn set_default_source(&self, source: &SourceInfo) -> Result<(), Error> {
+        let name = source.name.clone();
+        self.request(|reply| Request::SetDefaultSource { name, reply })
+            .await
+    }
+    /// `name` can be `@DEFAULT_SOURCE@`
+    pub async fn set_source_mute(&self, name: &str, mute: bool) -> Result<(), Error> {
+        let name = name.to_owned();
+        self.request(|reply| Request::SetSourceMute { name, mute, reply })
+            .await
+    }
+    /// `name` can be `@DEFAULT_SOURCE@`
+    pub async fn set_source_volume(&self, name: &str, percent: u8) -> Result<(), Error> {
+        let name = name.to_owned();
+        let source = self
+            .request(|reply| Request::GetSource { name, reply })
+            .await?;
+        let value = PaVolume::NORMAL.0 * u32::from(percent) / 100;
+        // the channel count has to match the source
+        let volume = vec![value; source.volume.len()];
+        let name = source.name;
+        self.request(|reply| Request::SetSourceVolume {
+            name,
+            volume,
+            reply,
+        })
+        .await
+    }
+    pub async fn list_sink_inputs(&self) -> Result<Vec<SinkInputInfo>, Error> {
+        self.request(Request::ListSinkInputs).await
+    }
+    pub async fn set_sink_input_volume(&self, index: u32, percent: u8) -> Result<(), Error> {
+        let input = self
+            .request(|reply| Request::GetSinkInput { index, reply })
+            .await?;
+        let value = PaVolume::NORMAL.0 * u32::from(percent) / 100;
+        // the channel count has to match the stream
+        let volume = vec![value; input.volume.len()];
+        self.request(|reply| Request::SetSinkInputVolume {
+            index,
+            volume,
+            reply,
+        })
+        .await
+    }
+    pub async fn set_sink_input_mute(&self, index: u32, mute: bool) -> Result<(), Error> {
+        self.request(|reply| Request::SetSinkInputMute { index, mute, reply })
+            .await
+    }
+    pub async fn move_sink_input(&self, index: u32, sink: &SinkInfo) -> Result<(), Error> {
+        let sink_name = sink.name.clone();
+        self.request(|reply| Request::MoveSinkInput {
+            index,
+            sink_name,
+            reply,
+        })
+        .await
+    }
+    pub async fn list_source_outputs(&self) -> Result<Vec<SourceOutputInfo>, Error> {
+        self.request(Request::ListSourceOutputs).await
+    }
+    pub async fn list_cards(&self) -> Result<Vec<CardInfo>, Error> {
+        self.request(Request::ListCards).await
+    }
+    pub async fn set_card_profile(&self, card: &CardInfo, profile: &str) -> Result<(), Error> {
+        let name = card.name.clone();
+        let profile = profile.to_owned();
+        self.request(|reply| Request::SetCardProfile {
+            name,
+            profile,
+            reply,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::VOLUME_NORM;
+
+    #[test]
+    fn channel_volumes_follow_the_channel_map() {
+        let volumes = channel_volumes_to_pa(vec![VOLUME_NORM, VOLUME_NORM / 2, 0]);
+        let volume = channel_volumes("front-left,front-right,lfe", &volumes);
+        let values: Vec<(&str, u32)> = volume
+            .iter()
+            .map(|(channel, v)| (channel.as_str(), v.value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("front-left", VOLUME_NORM),
+                ("front-right", VOLUME_NORM / 2),
+                ("lfe", 0)
+            ]
+        );
+        assert_eq!(volume["front-right"].value_percent, "50%");
+    }
+
+    #[test]
+    fn events_from_pa() {
+        let event = event_from_pa(Some(Facility::SinkInput), Some(Operation::New), 42);
+        assert_eq!(event.event_type, EventType::New);
+        assert_eq!(event.target, EventTarget::SinkInput);
+        assert_eq!(event.index, 42);
+
+        let event = event_from_pa(Some(Facility::Server), Some(Operation::Changed), 0);
+        assert_eq!(event.event_type, EventType::Change);
+        assert_eq!(event.target, EventTarget::Server);
+
+        // facilities without an event target, e.g. modules
+        let event = event_from_pa(Some(Facility::Module), Some(Operation::Removed), 7);
+        assert_eq!(event.event_type, EventType::Remove);
+        assert_eq!(event.target, EventTarget::Unknown);
+        let event = event_from_pa(None, None, 7);
+        assert_eq!(event.event_type, EventType::Unknown);
+    }
+}
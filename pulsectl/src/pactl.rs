@@ -0,0 +1,269 @@
+//! Fallback backend that runs `pactl` for every call and parses its json output.
+use async_io::Timer;
+use async_process::{Command, Stdio};
+use async_stream::stream;
+use futures_lite::{future, io::BufReader, prelude::*};
+use tokio_stream::Stream;
+
+use crate::{
+    CardInfo, Error, EventTarget, EventType, PulseEvent, ServerInfo, SinkInfo, SinkInputInfo,
+    SourceInfo, SourceOutputInfo, COMMAND_TIMEOUT,
+};
+
+fn spawn_error(e: std::io::Error) -> Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        Error::PactlNotFound
+    } else {
+        Error::IoError(e)
+    }
+}
+
+/// one line of `pactl --format json subscribe`
+#[derive(serde::Deserialize)]
+struct RawEvent {
+    index: u32,
+    event: String,
+    on: String,
+}
+
+fn parse_event(line: &str) -> Result<PulseEvent, Error> {
+    let raw: RawEvent = serde_json::from_str(line)?;
+    let event_type = match raw.event.as_str() {
+        "new" => EventType::New,
+        "change" => EventType::Change,
+        "remove" => EventType::Remove,
+        _ => EventType::Unknown,
+    };
+    let target = match raw.on.as_str() {
+        "sink" => EventTarget::Sink,
+        "source" => EventTarget::Source,
+        "sink-input" => EventTarget::SinkInput,
+        "source-output" => EventTarget::SourceOutput,
+        "card" => EventTarget::Card,
+        "server" => EventTarget::Server,
+        "client" => EventTarget::Client,
+        _ => EventTarget::Unknown,
+    };
+    Ok(PulseEvent {
+        event_type,
+        target,
+        index: raw.index,
+    })
+}
+
+pub struct Pulseaudio {
+    client_name: String,
+}
+impl Pulseaudio {
+    pub fn new(client_name: &str) -> Self {
+        Self {
+            client_name: client_name.to_owned(),
+        }
+    }
+    /// Streams the events of `pactl subscribe`. Lines that can't be parsed are passed on as
+    /// errors, the stream ends with an error when pactl exits.
+    pub async fn subscribe(&self) -> Result<impl Stream<Item = Result<PulseEvent, Error>>, Error> {
+        let mut child = Command::new("pactl")
+            .args(["--client-name", &self.client_name])
+            .args(["--format", "json", "subscribe"])
+            .stdout(Stdio::piped())
+            // the stream owns the child, dropping it must not leave pactl running
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(spawn_error)?;
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let s = stream! {
+            while let Some(line) = lines.next().await {
+                match line {
+                    Ok(line) if line.trim().is_empty() => {}
+                    Ok(line) => yield parse_event(&line),
+                    Err(e) => {
+                        yield Err(Error::IoError(e));
+                        break;
+                    }
+                }
+            }
+            // stdout is closed, pactl exited or is about to
+            let status = child.status().await;
+            yield Err(Error::PulseError(format!("pactl subscribe exited: {status:?}")));
+        };
+        Ok(s)
+    }
+    /// runs pactl with the arguments, fails if it takes too long or exits with an error
+    async fn run_pactl(&self, command: &str) -> Result<Vec<u8>, Error> {
+        assert!(!command.is_empty());
+        let output = Command::new("pactl")
+            .args(["--client-name", &self.client_name])
+            .args(command.split_whitespace())
+            .kill_on_drop(true)
+            .output();
+        let output = future::or(async { output.await.map_err(spawn_error) }, async {
+            Timer::after(COMMAND_TIMEOUT).await;
+            Err(Error::Timeout)
+        })
+        .await?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(Error::CommandFailed {
+                code: output.status.code(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            })
+        }
+    }
+    async fn run_command_with_output<T: serde::de::DeserializeOwned>(
+        &self,
+        command: &str,
+    ) -> Result<T, Error> {
+        let stdout = self.run_pactl(&format!("--format json {command}")).await?;
+        let json_string = String::from_utf8(stdout)?;
+        let result = serde_json::from_str(&json_string)?;
+        Ok(result)
+    }
+    async fn run_command(&self, command: &str) -> Result<(), Error> {
+        self.run_pactl(command).await?;
+        Ok(())
+    }
+    pub async fn set_default_sink(&self, sink: &SinkInfo) -> Result<(), Error> {
+        let cmd = String::from("set-default-sink ") + &sink.name;
+        self.run_command(&cmd).await
+    }
+    pub async fn server_info(&self) -> Result<ServerInfo, Error> {
+        self.run_command_with_output("info").await
+    }
+    pub async fn list_sinks(&self) -> Result<Vec<SinkInfo>, Error> {
+        let sinks: Vec<SinkInfo> = self.run_command_with_output("list sinks").await?;
+        Ok(sinks)
+    }
+    pub async fn get_default_sink(&self) -> Result<SinkInfo, Error> {
+        let default_sink_name = self.server_info().await?.default_sink_name;
+        let sinks = self.list_sinks().await?;
+        let default_sink = sinks
+            .into_iter()
+            .filter(|s| s.name == default_sink_name)
+            .last()
+            .ok_or(Error::PulseError("could not get default sink".to_owned()));
+        default_sink
+    }
+    pub async fn volume_up(&self, step: u8) -> Result<(), Error> {
+        self.run_command(&("set-sink-volume @DEFAULT_SINK@ +".to_owned() + &step.to_string() + "%"))
+            .await
+    }
+    pub async fn volume_down(&self, step: u8) -> Result<(), Error> {
+        self.run_command(&("set-sink-volume @DEFAULT_SINK@ -".to_owned() + &step.to_string() + "%"))
+            .await
+    }
+    pub async fn set_volume(&self, percent: u8) -> Result<(), Error> {
+        self.run_command(
+            &("set-sink-volume @DEFAULT_SINK@ ".to_owned() + &percent.to_string() + "%"),
+        )
+        .await
+    }
+    /// raw volumes in the order of the sink's channel map
+    pub async fn set_sink_channel_volumes(&self, name: &str, volumes: &[u32]) -> Result<(), Error> {
+        let volumes: Vec<String> = volumes.iter().map(u32::to_string).collect();
+        self.run_command(&format!("set-sink-volume {name} {}", volumes.join(" ")))
+            .await
+    }
+    pub async fn set_mute(&self, mute: bool) -> Result<(), Error> {
+        let value = if mute { "1" } else { "0" };
+        self.run_command(&("set-sink-mute @DEFAULT_SINK@ ".to_owned() + value))
+            .await
+    }
+    pub async fn toggle_mute(&self) -> Result<(), Error> {
+        self.run_command("set-sink-mute @DEFAULT_SINK@ toggle")
+            .await
+    }
+    pub async fn list_sources(&self) -> Result<Vec<SourceInfo>, Error> {
+        self.run_command_with_output("list sources").await
+    }
+    pub async fn get_default_source(&self) -> Result<SourceInfo, Error> {
+        let default_source_name = self.server_info().await?.default_source_name;
+        self.list_sources()
+            .await?
+            .into_iter()
+            .find(|s| s.name == default_source_name)
+            .ok_or(Error::PulseError("could not get default source".to_owned()))
+    }
+    pub async fn set_default_source(&self, source: &SourceInfo) -> Result<(), Error> {
+        self.run_command(&format!("set-default-source {}", &source.name))
+            .await
+    }
+    /// `name` can be `@DEFAULT_SOURCE@`
+    pub async fn set_source_mute(&self, name: &str, mute: bool) -> Result<(), Error> {
+        let value = if mute { "1" } else { "0" };
+        self.run_command(&format!("set-source-mute {name} {value}"))
+            .await
+    }
+    /// `name` can be `@DEFAULT_SOURCE@`
+    pub async fn set_source_volume(&self, name: &str, percent: u8) -> Result<(), Error> {
+        self.run_command(&format!("set-source-volume {name} {percent}%"))
+            .await
+    }
+    pub async fn list_sink_inputs(&self) -> Result<Vec<SinkInputInfo>, Error> {
+        self.run_command_with_output("list sink-inputs").await
+    }
+    pub async fn set_sink_input_volume(&self, index: u32, percent: u8) -> Result<(), Error> {
+        self.run_command(&format!("set-sink-input-volume {index} {percent}%"))
+            .await
+    }
+    pub async fn set_sink_input_mute(&self, index: u32, mute: bool) -> Result<(), Error> {
+        let value = if mute { "1" } else { "0" };
+        self.run_command(&format!("set-sink-input-mute {index} {value}"))
+            .await
+    }
+    pub async fn list_source_outputs(&self) -> Result<Vec<SourceOutputInfo>, Error> {
+        self.run_command_with_output("list source-outputs").await
+    }
+    pub async fn list_cards(&self) -> Result<Vec<CardInfo>, Error> {
+        self.run_command_with_output("list cards").await
+    }
+    pub async fn set_card_profile(&self, card: &CardInfo, profile: &str) -> Result<(), Error> {
+        self.run_command(&format!("set-card-profile {} {profile}", &card.name))
+            .await
+    }
+    pub async fn move_sink_input(&self, index: u32, sink: &SinkInfo) -> Result<(), Error> {
+        self.run_command(&format!("move-sink-input {index} {}", &sink.name))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio_test::block_on;
+
+    use super::*;
+
+    const TEST_CLIENT_NAME: &str = "test-client";
+
+    #[test]
+    fn parse_subscribe_lines() {
+        let event = parse_event(r#"{"index":42,"event":"new","on":"sink-input"}"#).unwrap();
+        assert_eq!(event.event_type, EventType::New);
+        assert_eq!(event.target, EventTarget::SinkInput);
+        assert_eq!(event.index, 42);
+
+        let event = parse_event(r#"{"index":0,"event":"change","on":"server"}"#).unwrap();
+        assert_eq!(event.target, EventTarget::Server);
+
+        assert!(matches!(
+            parse_event(r#"{"index":1,"event":"#),
+            Err(Error::JsonError(_))
+        ));
+    }
+
+    #[test]
+    fn run_error_cmd() {
+        let pulse = Pulseaudio::new(TEST_CLIENT_NAME);
+        {
+            let result = block_on(pulse.run_command("blub"));
+            let error = result.err().unwrap();
+            assert!(matches!(error, Error::CommandFailed { .. }));
+        }
+        {
+            let result = block_on(pulse.run_command("list abcde"));
+            let error = result.err().unwrap();
+            assert!(matches!(error, Error::CommandFailed { ref stderr, .. } if !stderr.is_empty()));
+        }
+    }
+}
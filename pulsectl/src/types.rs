@@ -1,58 +1,279 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, string::FromUtf8Error};
+use std::{collections::BTreeMap, fmt, string::FromUtf8Error};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum EventType {
+    New,
     Change,
     Remove,
     Unknown,
 }
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum EventTarget {
     Client,
     Sink,
+    Source,
+    SinkInput,
+    SourceOutput,
+    Card,
+    Server,
     Unknown,
 }
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct PulseEvent {
     pub event_type: EventType,
     pub target: EventTarget,
+    /// index of the object that changed, e.g. `SinkInfo::index`
+    pub index: u32,
 }
 
+/// raw volume of 100%
+pub const VOLUME_NORM: u32 = 0x10000;
+/// highest raw volume the server accepts
+pub const VOLUME_MAX: u32 = u32::MAX / 2;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Volume {
     pub value: u32,
     pub value_percent: String,
     pub db: String,
 }
+impl Volume {
+    /// formats a raw volume like `pactl --format json` does
+    pub fn from_raw(value: u32) -> Self {
+        let normal = u64::from(VOLUME_NORM);
+        let percent = (u64::from(value) * 100 + normal / 2) / normal;
+        // pulseaudio maps volumes to amplitudes with a cubic curve
+        let db = 60.0 * (f64::from(value) / f64::from(VOLUME_NORM)).log10();
+        Self {
+            value,
+            value_percent: format!("{percent}%"),
+            db: format!("{db:.2} dB"),
+        }
+    }
+}
+
+/// An absolute volume, e.g. `{"percent": 50}` or `{"db": -6.0}`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeLevel {
+    Percent(f64),
+    Db(f64),
+}
+impl VolumeLevel {
+    pub fn to_raw(self) -> u32 {
+        let factor = match self {
+            VolumeLevel::Percent(percent) => percent / 100.0,
+            VolumeLevel::Db(db) => 10f64.powf(db / 60.0),
+        };
+        (factor.max(0.0) * f64::from(VOLUME_NORM))
+            .round()
+            .min(f64::from(VOLUME_MAX)) as u32
+    }
+}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ServerInfo {
     pub server_string: String,
     pub default_sink_name: String,
+    pub default_source_name: String,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SinkInfo {
     pub index: u32,
     pub state: String,
     pub name: String,
+    /// human readable name, e.g. "Built-in Audio Analog Stereo"
+    #[serde(default)]
+    pub description: String,
+    pub mute: bool,
+    // channels, comma-seperated
+    pub channel_map: String,
+    // channel -> Volume
+    pub volume: BTreeMap<String, Volume>,
+    /// pulseaudio properties like `device.bus`
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+}
+impl SinkInfo {
+    /// raw volumes in the order of the channel map
+    pub fn channel_volumes(&self) -> Vec<u32> {
+        self.channel_map
+            .split(',')
+            .filter_map(|channel| self.volume.get(channel))
+            .map(|v| v.value)
+            .collect()
+    }
+    pub fn average_volume(&self) -> Volume {
+        let volumes = self.channel_volumes();
+        let sum: u64 = volumes.iter().map(|v| u64::from(*v)).sum();
+        Volume::from_raw((sum / volumes.len().max(1) as u64) as u32)
+    }
+    /// the volume pulseaudio shows for the whole sink, independent of the balance
+    pub fn max_volume(&self) -> Volume {
+        Volume::from_raw(self.channel_volumes().into_iter().max().unwrap_or(0))
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SourceInfo {
+    pub index: u32,
+    pub state: String,
+    pub name: String,
+    pub mute: bool,
+    // channels, comma-seperated
+    pub channel_map: String,
+    // channel -> Volume
+    pub volume: BTreeMap<String, Volume>,
+    /// name of the sink for monitor sources, "n/a" for real inputs
+    pub monitor_of_sink: String,
+}
+impl SourceInfo {
+    pub fn is_monitor(&self) -> bool {
+        self.monitor_of_sink != "n/a"
+    }
+}
+/// A stream of an application that plays on a sink
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SinkInputInfo {
+    pub index: u32,
+    /// index of the sink the stream plays on
+    pub sink: u32,
     pub mute: bool,
+    /// paused streams stay connected to their sink
+    pub corked: bool,
     // channels, comma-seperated
     pub channel_map: String,
     // channel -> Volume
-    pub volume: HashMap<String, Volume>,
+    pub volume: BTreeMap<String, Volume>,
+    /// pulseaudio properties like `application.name`
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+}
+impl SinkInputInfo {
+    fn property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(String::as_str)
+    }
+    pub fn application_name(&self) -> Option<&str> {
+        self.property("application.name")
+    }
+    pub fn binary(&self) -> Option<&str> {
+        self.property("application.process.binary")
+    }
+    pub fn media_name(&self) -> Option<&str> {
+        self.property("media.name")
+    }
+}
+
+/// A stream of an application that records from a source
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SourceOutputInfo {
+    pub index: u32,
+    /// index of the source the stream records from
+    pub source: u32,
+    pub mute: bool,
+    pub corked: bool,
+    /// pulseaudio properties like `application.name`
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+}
+impl SourceOutputInfo {
+    pub fn application_name(&self) -> Option<&str> {
+        self.properties.get("application.name").map(String::as_str)
+    }
+}
+
+/// A sound card, its profiles decide which sinks and sources it provides
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CardInfo {
+    pub index: u32,
+    pub name: String,
+    /// profile name -> profile
+    pub profiles: BTreeMap<String, CardProfile>,
+    pub active_profile: Option<String>,
+    /// pulseaudio properties like `device.description`
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CardProfile {
+    pub description: String,
+    pub sinks: u32,
+    pub sources: u32,
+    pub priority: u32,
+    /// false if e.g. nothing is plugged in
+    pub available: bool,
+}
+impl CardInfo {
+    pub fn description(&self) -> Option<&str> {
+        self.properties
+            .get("device.description")
+            .map(String::as_str)
+    }
+    /// names of the profiles that can be activated, the preferred ones first
+    pub fn available_profiles(&self) -> Vec<String> {
+        let mut profiles: Vec<(&String, &CardProfile)> =
+            self.profiles.iter().filter(|(_, p)| p.available).collect();
+        profiles.sort_by(|a, b| b.1.priority.cmp(&a.1.priority).then(a.0.cmp(b.0)));
+        profiles.into_iter().map(|(name, _)| name.clone()).collect()
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
+    /// the output could not be parsed
     JsonError(serde_json::Error),
     PulseError(String),
     Utf8Error(FromUtf8Error),
+    /// pactl could not be run
+    IoError(std::io::Error),
+    /// pactl is not installed
+    PactlNotFound,
+    /// pactl exited with an error
+    CommandFailed {
+        code: Option<i32>,
+        stderr: String,
+    },
+    /// the server did not answer in time
+    Timeout,
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::JsonError(e) => write!(f, "could not parse the output: {e}"),
+            Error::PulseError(message) => write!(f, "{message}"),
+            Error::Utf8Error(e) => write!(f, "output is not valid utf8: {e}"),
+            Error::IoError(e) => write!(f, "could not run pactl: {e}"),
+            Error::PactlNotFound => write!(f, "pactl is not installed"),
+            Error::CommandFailed {
+                code: Some(code),
+                stderr,
+            } => write!(f, "pactl exited with {code}: {stderr}"),
+            Error::CommandFailed { code: None, stderr } => {
+                write!(f, "pactl was killed: {stderr}")
+            }
+            Error::Timeout => write!(f, "pulseaudio did not answer in time"),
+        }
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::JsonError(e) => Some(e),
+            Error::Utf8Error(e) => Some(e),
+            Error::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 impl From<FromUtf8Error> for Error {
     fn from(e: FromUtf8Error) -> Self {
         Self::Utf8Error(e)
     }
 }
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
         Self::JsonError(e)
@@ -1,15 +1,7 @@
 use ddc_hi::{Ddc, Display};
 
-fn input_value_to_string(val: u8) -> &'static str {
-    match val {
-        0x0f => "DisplayPort 1",
-        0x10 => "DisplayPort 2",
-        0x11 => "HDMI 1",
-        0x12 => "HDMI 2",
-        _ => "Unknown",
-    }
-}
-
+#[path = "../mccs.rs"]
+mod mccs;
 
 fn main() {
     for mut display in Display::enumerate() {
@@ -23,8 +15,11 @@ fn main() {
         );
         let cap = display.handle.capabilities().unwrap();
         let input_values = cap.vcp_features.get(&0x60).unwrap();
-        for i in input_values.values() {
-            dbg!(i);
+        for (value, name) in &input_values.values {
+            let name = name
+                .as_deref()
+                .unwrap_or_else(|| mccs::input_value_to_string(*value));
+            println!("  input {value:#04x}: {name}");
         }
     }
 }
@@ -0,0 +1,208 @@
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rumqttc::{self, AsyncClient, QoS};
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::homeassistant::Availability;
+use crate::module::{Module, Ready};
+
+/// video4linux devices, webcams and capture cards
+const VIDEO_DEVICE_PREFIX: &str = "/dev/video";
+
+/// a process that has a video device open
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+struct CameraUser {
+    pid: u32,
+    name: String,
+    device: String,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+struct CameraState {
+    in_use: bool,
+    processes: Vec<CameraUser>,
+}
+
+/// Looks through the open files of every process in `proc`. Processes of other users can't
+/// be inspected without root, so only the user's own applications are found.
+fn scan_video_users(proc: &Path) -> Vec<CameraUser> {
+    let Ok(processes) = std::fs::read_dir(proc) else {
+        return Vec::new();
+    };
+    let mut users = Vec::new();
+    for process in processes.flatten() {
+        let Some(pid) = process.file_name().to_str().and_then(|p| p.parse().ok()) else {
+            continue;
+        };
+        // the process might have exited in the meantime
+        let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        let devices = fds.flatten().filter_map(|fd| {
+            let target = std::fs::read_link(fd.path()).ok()?;
+            let target = target.to_str()?;
+            target
+                .starts_with(VIDEO_DEVICE_PREFIX)
+                .then(|| target.to_owned())
+        });
+        for device in devices {
+            let name = std::fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+            let user = CameraUser {
+                pid,
+                name: name.trim().to_owned(),
+                device,
+            };
+            if !users.contains(&user) {
+                users.push(user);
+            }
+        }
+    }
+    users.sort_by_key(|u| u.pid);
+    users
+}
+
+async fn get_state() -> anyhow::Result<CameraState> {
+    let processes = tokio::task::spawn_blocking(|| scan_video_users(Path::new("/proc"))).await?;
+    Ok(CameraState {
+        in_use: !processes.is_empty(),
+        processes,
+    })
+}
+
+async fn autodiscover(config: &Config, client: &AsyncClient) {
+    let name = format!("{}in_use", &config.camera.name_prefix);
+    let sensor = config.build_binary_sensor(
+        config.camera.state_topic.clone(),
+        config.camera.availability.clone(),
+        name.clone(),
+        name,
+        format!(
+            "{{{{ '{on}' if value_json.in_use == true else '{off}' }}}}",
+            on = &config.switch_on_value,
+            off = &config.switch_off_value,
+        ),
+        None,
+        "{{ {'processes': value_json.processes} | tojson }}".to_owned(),
+    );
+    config.publish_autodiscover(client, &sensor).await;
+}
+
+// there are no notifications for opened files, so /proc is polled
+async fn camera_state_task(
+    client: &AsyncClient,
+    config: &Config,
+    mut connects: watch::Receiver<u32>,
+    mut ready: Ready,
+) -> anyhow::Result<()> {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.camera.poll_interval.get()));
+    // only changes are published, the state is retained
+    let mut published: Option<CameraState> = None;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            Ok(()) = connects.changed() => {
+                // the broker might have lost the state
+                published = None;
+            }
+        }
+        let state = get_state().await?;
+        ready.connected();
+        if published.as_ref() == Some(&state) {
+            continue;
+        }
+        log::debug!("Publishing camera state {:?}", &state);
+        client
+            .publish(
+                &config.camera.state_topic,
+                QoS::AtLeastOnce,
+                true,
+                serde_json::to_string(&state)?,
+            )
+            .await?;
+        published = Some(state);
+    }
+}
+
+pub struct CameraModule {
+    config: Config,
+}
+
+impl CameraModule {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Module for CameraModule {
+    fn name(&self) -> &str {
+        "camera"
+    }
+
+    fn availability(&self) -> &Availability {
+        &self.config.camera.availability
+    }
+
+    fn command_topics(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    async fn discovery(&self, client: &AsyncClient) -> anyhow::Result<()> {
+        autodiscover(&self.config, client).await;
+        Ok(())
+    }
+
+    async fn state(
+        &self,
+        client: AsyncClient,
+        connects: watch::Receiver<u32>,
+        ready: Ready,
+    ) -> anyhow::Result<()> {
+        camera_state_task(&client, &self.config, connects, ready).await
+    }
+
+    async fn command(
+        &self,
+        _client: &AsyncClient,
+        topic: &str,
+        _payload: &str,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("the camera module has no commands, got one on {topic}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_processes_with_video_devices() {
+        let proc = std::env::temp_dir().join(format!("desktop-camera-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&proc);
+        for (pid, name, target) in [
+            ("100", "firefox", "/dev/video0"),
+            ("200", "bash", "/dev/null"),
+        ] {
+            let fd = proc.join(pid).join("fd");
+            std::fs::create_dir_all(&fd).unwrap();
+            std::fs::write(proc.join(pid).join("comm"), format!("{name}\n")).unwrap();
+            std::os::unix::fs::symlink(target, fd.join("3")).unwrap();
+        }
+        std::fs::create_dir_all(proc.join("self")).unwrap();
+
+        let users = scan_video_users(&proc);
+        std::fs::remove_dir_all(&proc).unwrap();
+        assert_eq!(
+            users,
+            vec![CameraUser {
+                pid: 100,
+                name: "firefox".to_owned(),
+                device: "/dev/video0".to_owned(),
+            }]
+        );
+    }
+}
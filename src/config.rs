@@ -1,5 +1,12 @@
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+use std::path::Path;
+use std::path::PathBuf;
 use std::time::Duration;
 
+use anyhow::Context;
+use chrono::NaiveTime;
+
 use rumqttc::AsyncClient;
 use rumqttc::EventLoop;
 use rumqttc::LastWill;
@@ -9,9 +16,12 @@ use serde::Deserialize;
 use serde::Serialize;
 
 use crate::homeassistant::Availability;
+use crate::homeassistant::BinarySensor;
+use crate::homeassistant::Button;
 use crate::homeassistant::Component;
 use crate::homeassistant::ComponentCommon;
 use crate::homeassistant::Device;
+use crate::homeassistant::Number;
 use crate::homeassistant::Select;
 use crate::homeassistant::Switch;
 
@@ -19,16 +29,14 @@ static CONFIG_STR: &str = include_str!(concat!(
     env!("CARGO_MANIFEST_DIR"),
     "/resources/default_config.yaml"
 ));
-
-pub trait MqttModuleConfig {
-    fn client_id(&self) -> &str;
-    fn last_will_topic(&self) -> &str;
-    fn last_will_payload(&self) -> String;
-}
+/// location of the user config relative to $XDG_CONFIG_HOME (or ~/.config)
+const USER_CONFIG_PATH: &str = "desktop/config.yaml";
 
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct MqttConfig {
+    /// all modules share one connection with this id
+    pub client_id: String,
     pub server_host: String,
     pub server_port: u16,
     pub keep_alive: u64,
@@ -45,65 +53,284 @@ pub struct HomeAssistantConfig {
     pub autodiscover_prefix: String,
     pub device: Device,
 }
-#[derive(Deserialize, Serialize)]
+/// A custom script, exposed as a button if `command` is set or as a switch if `on_command`
+/// and `off_command` are set. Commands are run with `sh -c`.
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ScriptConfig {
-    command: String,
+    pub name: String,
+    /// button: run once when pressed
+    pub command: Option<String>,
+    /// switch: run when turned on
+    pub on_command: Option<String>,
+    /// switch: run when turned off
+    pub off_command: Option<String>,
+    /// switch: exit code 0 means on, polled every `state_interval` seconds
+    pub state_command: Option<String>,
+    #[serde(default = "default_script_state_interval")]
+    pub state_interval: u64,
+    /// seconds after which the command is killed
+    #[serde(default = "default_script_timeout")]
+    pub timeout: u64,
+    pub working_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+fn default_script_timeout() -> u64 {
+    30
+}
+fn default_script_state_interval() -> u64 {
+    60
+}
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptRunnerConfig {
+    pub enabled: bool,
+    pub name_prefix: String,
+    /// each script gets `<topic_prefix>/<name>/command` and `<topic_prefix>/<name>/state`
+    pub topic_prefix: String,
+    pub availability: Availability,
 }
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct PulseAudioConfig {
-    pub mqtt_name: String,
+    pub enabled: bool,
+    pub name_prefix: String,
     pub state_topic: String,
     pub command_topic: String,
+    /// failed commands are reported here
+    pub error_topic: String,
     pub availability: Availability,
+    /// sink name -> name shown in homeassistant, instead of the sink's description
+    pub sink_aliases: HashMap<String, String>,
+    /// sinks offered by the select and `CycleSinks`, `*` matches anything, empty means all
+    pub include_sinks: Vec<String>,
+    /// sinks that are never offered, even if they are included
+    pub exclude_sinks: Vec<String>,
+    #[serde(default)]
+    pub presets: Vec<AudioPresetConfig>,
+    /// percent no volume command goes past, unless the sink has its own limit
+    pub max_volume: u8,
+    /// limits for single sinks, the first matching one is used
+    pub sink_max_volume: Vec<SinkVolumeLimitConfig>,
+    /// milliseconds `SetVolume` fades to the new volume, 0 sets it at once
+    pub volume_ramp_ms: u64,
+    /// lowers the limit of every sink during the night
+    pub quiet_hours: Option<QuietHoursConfig>,
 }
-impl MqttModuleConfig for PulseAudioConfig {
-    fn client_id(&self) -> &str {
-        &self.mqtt_name
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SinkVolumeLimitConfig {
+    /// name of the sink, `*` matches anything
+    pub sink: String,
+    /// percent
+    pub max_volume: u8,
+}
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    /// local time, e.g. "22:00"
+    pub start: NaiveTime,
+    /// may be before `start`, then the quiet hours last over midnight
+    pub end: NaiveTime,
+    /// percent
+    pub max_volume: u8,
+}
+impl QuietHoursConfig {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
     }
+}
+/// A named audio state, applied with one command. Unset values are left alone and
+/// don't matter when checking which preset is active.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AudioPresetConfig {
+    pub name: String,
+    /// name of the default sink, `*` matches anything
+    pub sink: String,
+    /// percent
+    pub volume: Option<u8>,
+    pub mute: Option<bool>,
+    /// applied first, the profile decides which sinks the card has
+    pub card_profile: Option<PresetCardProfileConfig>,
+}
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PresetCardProfileConfig {
+    /// name of the card, `*` matches anything
+    pub card: String,
+    pub profile: String,
+}
+impl PulseAudioConfig {
+    pub fn sink_allowed(&self, name: &str) -> bool {
+        let included = self.include_sinks.is_empty()
+            || self.include_sinks.iter().any(|p| wildcard_match(p, name));
+        included && !self.exclude_sinks.iter().any(|p| wildcard_match(p, name))
+    }
+    /// Display names of the sinks in the same order. Sinks often share a description, later
+    /// ones get a number so every name selects one sink.
+    pub fn sink_display_names(&self, sinks: &[pulsectl::SinkInfo]) -> Vec<String> {
+        unique_names(sinks.iter().map(|s| self.sink_display_name(s)))
+    }
+    /// the alias, or the description pulseaudio provides
+    fn sink_display_name(&self, sink: &pulsectl::SinkInfo) -> String {
+        if let Some(alias) = self.sink_aliases.get(&sink.name) {
+            alias.clone()
+        } else if !sink.description.is_empty() {
+            sink.description.clone()
+        } else {
+            sink.name.clone()
+        }
+    }
+    /// the loudest a sink may be set to at `time`, in percent
+    pub fn volume_limit(&self, sink_name: &str, time: NaiveTime) -> u8 {
+        let limit = self
+            .sink_max_volume
+            .iter()
+            .find(|l| wildcard_match(&l.sink, sink_name))
+            .map_or(self.max_volume, |l| l.max_volume);
+        match &self.quiet_hours {
+            Some(quiet) if quiet.contains(time) => limit.min(quiet.max_volume),
+            _ => limit,
+        }
+    }
+    /// the loudest any sink may be set to, the range of the volume entity
+    pub fn highest_volume_limit(&self) -> u8 {
+        self.sink_max_volume
+            .iter()
+            .map(|l| l.max_volume)
+            .fold(self.max_volume, u8::max)
+    }
+}
 
-    fn last_will_topic(&self) -> &str {
-        &self.availability.topic
+fn unique_names(names: impl Iterator<Item = String>) -> Vec<String> {
+    let mut unique: Vec<String> = Vec::new();
+    for name in names {
+        let mut candidate = name.clone();
+        let mut n = 2;
+        while unique.contains(&candidate) {
+            candidate = format!("{name} ({n})");
+            n += 1;
+        }
+        unique.push(candidate);
     }
+    unique
+}
 
-    fn last_will_payload(&self) -> String {
-        self.availability.payload_not_available.clone()
+/// matches `text` against `pattern`, where `*` matches any sequence of characters
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always returns at least one part
+    let first = parts.next().unwrap();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no wildcard
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
     }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+/// Settings for one output in a display profile
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileOutputConfig {
+    /// output name (e.g. "DP-1") or "<make> <model> <serial>" like in kanshi
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub enable: bool,
+    /// e.g. "2560x1440@144Hz"
+    pub mode: Option<String>,
+    pub position: Option<[i32; 2]>,
+    pub scale: Option<f64>,
+    pub transform: Option<String>,
+}
+fn default_true() -> bool {
+    true
+}
+/// A named display layout, it matches when exactly its outputs are connected
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub name: String,
+    pub outputs: Vec<ProfileOutputConfig>,
 }
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct SwayConfig {
+    pub enabled: bool,
     pub name_prefix: String,
-    pub mqtt_name: String,
     pub state_topic: String,
     pub command_topic: String,
     pub availability: Availability,
+    #[serde(default)]
+    pub profiles: Vec<ProfileConfig>,
+    /// apply the matching profile whenever the connected outputs change
+    #[serde(default)]
+    pub auto_apply_profile: bool,
 }
-impl MqttModuleConfig for SwayConfig {
-    fn client_id(&self) -> &str {
-        &self.mqtt_name
-    }
-
-    fn last_will_topic(&self) -> &str {
-        &self.availability.topic
-    }
-
-    fn last_will_payload(&self) -> String {
-        self.availability.payload_not_available.clone()
-    }
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WlrConfig {
+    pub enabled: bool,
+    pub name_prefix: String,
+    pub state_topic: String,
+    pub command_topic: String,
+    pub availability: Availability,
+}
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DdcConfig {
+    pub enabled: bool,
+    pub name_prefix: String,
+    pub state_topic: String,
+    pub command_topic: String,
+    /// seconds between reading the monitor values, ddc/ci has no change notifications
+    pub poll_interval: NonZeroU64,
+    pub availability: Availability,
+}
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CameraConfig {
+    pub enabled: bool,
+    pub name_prefix: String,
+    pub state_topic: String,
+    /// seconds between looking for processes that use a camera
+    pub poll_interval: NonZeroU64,
+    pub availability: Availability,
 }
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub app_name: String,
     pub mqtt: MqttConfig,
+    /// availability of the whole device, set to offline by the broker if the connection is lost
+    pub availability: Availability,
     pub homeassistant: HomeAssistantConfig,
     pub pulseaudio: PulseAudioConfig,
     pub sway: SwayConfig,
+    pub wlr: WlrConfig,
+    pub ddc: DdcConfig,
+    pub camera: CameraConfig,
     pub switch_on_value: String,
     pub switch_off_value: String,
-    // scripts: Vec<ScriptConfig>,
+    pub script_runner: ScriptRunnerConfig,
+    #[serde(default)]
+    pub scripts: Vec<ScriptConfig>,
+    /// seconds to wait before restarting a module whose backend is not available
+    pub retry_interval: u64,
 }
 
 impl Config {
@@ -113,23 +340,52 @@ impl Config {
         config
     }
 
-    pub fn get_client(&self, mqtt_config: &dyn MqttModuleConfig) -> (AsyncClient, EventLoop) {
+    /// Loads the config from `path` if given, otherwise from the XDG config directory.
+    /// Values from the user file are layered over the built-in defaults, so partial files work.
+    /// If no path is given and no user config exists, the defaults are used as is.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(p) => Some(p.to_owned()),
+            None => user_config_path().filter(|p| p.exists()),
+        };
+        let Some(path) = path else {
+            log::info!("No user config found, using default config");
+            return Ok(Self::new());
+        };
+        log::info!("Loading config from {}", path.display());
+        let user_str = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        Self::from_user_str(&user_str)
+            .with_context(|| format!("parsing config file {}", path.display()))
+    }
+
+    pub(crate) fn from_user_str(user_str: &str) -> anyhow::Result<Self> {
+        let mut config: serde_yaml::Value = serde_yaml::from_str(CONFIG_STR)?;
+        let user: serde_yaml::Value = serde_yaml::from_str(user_str)?;
+        // an empty file deserializes to null, which should just mean "no overrides"
+        if !user.is_null() {
+            merge_yaml(&mut config, user);
+        }
+        Ok(serde_yaml::from_value(config)?)
+    }
+
+    pub fn get_client(&self) -> (AsyncClient, EventLoop) {
         log::debug!(
             "Connecting to mqtt broker at {}:{} with client_id: {}",
             self.mqtt.server_host,
             self.mqtt.server_port,
-            mqtt_config.client_id()
+            &self.mqtt.client_id
         );
 
         let mut mqttoptions = MqttOptions::new(
-            mqtt_config.client_id(),
+            &self.mqtt.client_id,
             &self.mqtt.server_host,
             self.mqtt.server_port,
         )
         .set_keep_alive(Duration::from_secs(self.mqtt.keep_alive))
         .set_last_will(LastWill::new(
-            mqtt_config.last_will_topic(),
-            mqtt_config.last_will_payload(),
+            &self.availability.topic,
+            self.availability.payload_not_available.clone(),
             QoS::AtLeastOnce,
             true, // retain so that homeassistant knows this entity is offline even after restarting
         ))
@@ -141,6 +397,22 @@ impl Config {
         AsyncClient::new(mqttoptions, 10)
     }
 
+    /// entities are available if both the device and their module are
+    fn component_common(
+        &self,
+        name: String,
+        unique_id: String,
+        availability: Availability,
+    ) -> ComponentCommon {
+        ComponentCommon {
+            name,
+            unique_id,
+            device: self.homeassistant.device.clone(),
+            availability: vec![self.availability.clone(), availability],
+            availability_mode: "all".to_owned(),
+        }
+    }
+
     pub fn build_switch(
         &self,
         command_topic: String,
@@ -154,12 +426,7 @@ impl Config {
         payload_off: String,
         json_attributes_topic: String,
     ) -> Switch {
-        let common = ComponentCommon {
-            name,
-            unique_id,
-            device: self.homeassistant.device.clone(),
-            availability,
-        };
+        let common = self.component_common(name, unique_id, availability);
         Switch {
             command_topic,
             state_topic: state_topic.clone(),
@@ -183,24 +450,89 @@ impl Config {
         name: String,
         unique_id: String,
         value_template: String,
+        command_template: Option<String>,
         json_attributes_template: String,
     ) -> Select {
-        let common = ComponentCommon {
-            name,
-            unique_id,
-            device: self.homeassistant.device.clone(),
-            availability,
-        };
+        let common = self.component_common(name, unique_id, availability);
         Select {
             command_topic,
             state_topic: state_topic.clone(),
             common,
             options,
             value_template,
+            command_template,
             json_attributes_template,
             json_attributes_topic: state_topic,
         }
     }
+    pub fn build_number(
+        &self,
+        command_topic: String,
+        state_topic: String,
+        availability: Availability,
+        name: String,
+        unique_id: String,
+        range: (f64, f64, f64),
+        unit_of_measurement: Option<String>,
+        value_template: String,
+        command_template: String,
+    ) -> Number {
+        let common = self.component_common(name, unique_id, availability);
+        let (min, max, step) = range;
+        Number {
+            command_topic,
+            state_topic,
+            common,
+            min,
+            max,
+            step,
+            mode: "slider".to_owned(),
+            unit_of_measurement,
+            value_template,
+            command_template,
+        }
+    }
+    /// the value template has to render `switch_on_value` or `switch_off_value`
+    pub fn build_binary_sensor(
+        &self,
+        state_topic: String,
+        availability: Availability,
+        name: String,
+        unique_id: String,
+        value_template: String,
+        device_class: Option<String>,
+        json_attributes_template: String,
+    ) -> BinarySensor {
+        let common = self.component_common(name, unique_id, availability);
+        BinarySensor {
+            state_topic: state_topic.clone(),
+            common,
+            value_template,
+            payload_on: self.switch_on_value.clone(),
+            payload_off: self.switch_off_value.clone(),
+            device_class,
+            json_attributes_topic: state_topic,
+            json_attributes_template,
+        }
+    }
+    pub fn build_button(
+        &self,
+        command_topic: String,
+        availability: Availability,
+        name: String,
+        unique_id: String,
+        json_attributes_topic: String,
+        json_attributes_template: String,
+    ) -> Button {
+        let common = self.component_common(name, unique_id, availability);
+        Button {
+            command_topic,
+            common,
+            payload_press: "PRESS".to_owned(),
+            json_attributes_topic,
+            json_attributes_template,
+        }
+    }
     pub fn get_autodiscover_topic(&self, component: &impl Component) -> String {
         let component_str = component.component_str();
         let prefix = self.homeassistant.autodiscover_prefix.clone();
@@ -208,6 +540,9 @@ impl Config {
         return format!("{prefix}/{component_str}/{object_id}/config");
     }
     pub async fn publish_autodiscover(&self, client: &AsyncClient, component: &impl Component) {
+        if !self.homeassistant.autodiscover {
+            return;
+        }
         let topic = self.get_autodiscover_topic(component);
         let payload = component.to_json();
         log::debug!(
@@ -220,6 +555,43 @@ impl Config {
             .await
             .expect("publish autodiscover");
     }
+    /// an empty retained config removes the entity from homeassistant
+    pub async fn remove_autodiscover(&self, client: &AsyncClient, topic: &str) {
+        if !self.homeassistant.autodiscover {
+            return;
+        }
+        log::debug!("Removing autodiscover. topic: {}", topic);
+        client
+            .publish(topic, QoS::AtLeastOnce, true, "")
+            .await
+            .expect("publish autodiscover");
+    }
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join(USER_CONFIG_PATH))
+}
+
+/// Recursively merges `overlay` into `base`. Mappings are merged key by key,
+/// every other value (including sequences) is replaced.
+fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_yaml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
 }
 
 #[cfg(test)]
@@ -230,4 +602,144 @@ mod test {
     fn config_can_construct() {
         let _ = super::Config::new();
     }
+
+    #[test]
+    fn partial_user_config_overrides_defaults() {
+        let config = Config::from_user_str(
+            "mqtt:\n  server_host: \"broker.lan\"\n  user: \"desk\"\n  password: \"secret\"\n",
+        )
+        .unwrap();
+        let default = Config::new();
+        assert_eq!(config.mqtt.server_host, "broker.lan");
+        assert_eq!(config.mqtt.user.as_deref(), Some("desk"));
+        assert_eq!(config.mqtt.server_port, default.mqtt.server_port);
+        assert_eq!(config.sway.state_topic, default.sway.state_topic);
+    }
+
+    #[test]
+    fn empty_user_config_uses_defaults() {
+        let config = Config::from_user_str("").unwrap();
+        assert_eq!(config.app_name, Config::new().app_name);
+    }
+
+    #[test]
+    fn modules_can_be_disabled() {
+        let config = Config::from_user_str("pulseaudio:\n  enabled: false\n").unwrap();
+        assert!(!config.pulseaudio.enabled);
+        assert!(config.sway.enabled);
+    }
+
+    #[test]
+    fn scripts_from_user_config() {
+        let config = Config::from_user_str(
+            "scripts:\n  - name: \"lock\"\n    command: \"loginctl lock-session\"\n    env:\n      FOO: \"bar\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.scripts.len(), 1);
+        let script = &config.scripts[0];
+        assert_eq!(script.command.as_deref(), Some("loginctl lock-session"));
+        assert_eq!(script.timeout, default_script_timeout());
+        assert_eq!(script.env.get("FOO").map(String::as_str), Some("bar"));
+    }
+
+    #[test]
+    fn sway_profiles_from_user_config() {
+        let config = Config::from_user_str(
+            "sway:\n  auto_apply_profile: true\n  profiles:\n    - name: \"docked\"\n      outputs:\n        - name: \"eDP-1\"\n          enable: false\n        - name: \"DP-1\"\n          mode: \"2560x1440@144Hz\"\n          position: [0, 0]\n",
+        )
+        .unwrap();
+        assert!(config.sway.auto_apply_profile);
+        let profile = &config.sway.profiles[0];
+        assert_eq!(profile.name, "docked");
+        assert!(!profile.outputs[0].enable);
+        assert!(profile.outputs[1].enable);
+        assert_eq!(profile.outputs[1].position, Some([0, 0]));
+        // the other sway settings still come from the defaults
+        assert_eq!(config.sway.state_topic, Config::new().sway.state_topic);
+    }
+
+    #[test]
+    fn sink_patterns() {
+        let config = Config::from_user_str(
+            "pulseaudio:\n  include_sinks: [\"alsa_output.*\", \"bluez_*\"]\n  exclude_sinks: [\"*.monitor\", \"*hdmi*\"]\n",
+        )
+        .unwrap();
+        let pulse = &config.pulseaudio;
+        assert!(pulse.sink_allowed("alsa_output.pci-0000_0b_00.4.analog-stereo"));
+        assert!(pulse.sink_allowed("bluez_output.AA_BB.1"));
+        assert!(!pulse.sink_allowed("alsa_output.pci-0000_01_00.1.hdmi-stereo"));
+        assert!(!pulse.sink_allowed("auto_null"));
+        assert!(Config::new().pulseaudio.sink_allowed("auto_null"));
+        assert!(super::wildcard_match("a*b*c", "abc"));
+        assert!(!super::wildcard_match("a*bc", "abc_"));
+        assert!(!super::wildcard_match("ab*b", "ab"));
+    }
+
+    #[test]
+    fn display_names_are_unique() {
+        let names = ["Speakers", "HDMI", "Speakers", "Speakers (2)", "Speakers"];
+        assert_eq!(
+            unique_names(names.into_iter().map(str::to_owned)),
+            vec![
+                "Speakers",
+                "HDMI",
+                "Speakers (2)",
+                "Speakers (2) (2)",
+                "Speakers (3)"
+            ]
+        );
+    }
+
+    #[test]
+    fn audio_presets_from_user_config() {
+        let config = Config::from_user_str(
+            "pulseaudio:\n  presets:\n    - name: \"headphones\"\n      sink: \"bluez_output.*\"\n      volume: 30\n      card_profile:\n        card: \"bluez_card.*\"\n        profile: \"a2dp-sink\"\n    - name: \"speakers\"\n      sink: \"alsa_output.*analog-stereo\"\n      mute: false\n",
+        )
+        .unwrap();
+        let presets = &config.pulseaudio.presets;
+        assert_eq!(presets.len(), 2);
+        assert_eq!(presets[0].volume, Some(30));
+        assert_eq!(
+            presets[0].card_profile.as_ref().unwrap().profile,
+            "a2dp-sink"
+        );
+        assert_eq!(presets[1].mute, Some(false));
+        assert!(presets[1].card_profile.is_none());
+    }
+
+    #[test]
+    fn volume_limits() {
+        let config = Config::from_user_str(
+            "pulseaudio:\n  max_volume: 90\n  sink_max_volume:\n    - sink: \"bluez_output.*\"\n      max_volume: 50\n  quiet_hours:\n    start: \"22:00\"\n    end: \"07:00\"\n    max_volume: 30\n",
+        )
+        .unwrap();
+        let pulse = &config.pulseaudio;
+        let day = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let night = NaiveTime::from_hms_opt(23, 30, 0).unwrap();
+        let morning = NaiveTime::from_hms_opt(6, 59, 0).unwrap();
+        assert_eq!(pulse.volume_limit("bluez_output.AA_BB.1", day), 50);
+        assert_eq!(pulse.volume_limit("alsa_output.analog-stereo", day), 90);
+        assert_eq!(pulse.volume_limit("alsa_output.analog-stereo", night), 30);
+        assert_eq!(pulse.volume_limit("alsa_output.analog-stereo", morning), 30);
+        assert_eq!(
+            Config::new().pulseaudio.volume_limit("auto_null", night),
+            100
+        );
+        assert_eq!(pulse.highest_volume_limit(), 90);
+        let louder_sink =
+            Config::from_user_str("pulseaudio:\n  max_volume: 60\n  sink_max_volume:\n    - sink: \"alsa_output.*\"\n      max_volume: 80\n").unwrap();
+        assert_eq!(louder_sink.pulseaudio.highest_volume_limit(), 80);
+        assert!(Config::from_user_str("pulseaudio:\n  quiet_hours:\n    start: \"late\"\n    end: \"07:00\"\n    max_volume: 30\n").is_err());
+    }
+
+    #[test]
+    fn zero_poll_intervals_are_rejected() {
+        assert!(Config::from_user_str("ddc:\n  poll_interval: 0\n").is_err());
+        assert!(Config::from_user_str("camera:\n  poll_interval: 0\n").is_err());
+    }
+
+    #[test]
+    fn unknown_user_keys_are_rejected() {
+        assert!(Config::from_user_str("mqtt:\n  server_hots: \"typo\"\n").is_err());
+    }
 }
@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ddc_hi::{Ddc, Display};
+use rumqttc::{self, AsyncClient as MqttClient, QoS};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::config::Config;
+use crate::homeassistant::{entity_key, Availability};
+use crate::mccs::input_value_to_string;
+use crate::module::{Module, Ready};
+
+const VCP_BRIGHTNESS: u8 = 0x10;
+const VCP_CONTRAST: u8 = 0x12;
+const VCP_INPUT_SOURCE: u8 = 0x60;
+/// DDC/CI commands fail randomly on a lot of monitors, so every read/write is retried
+const DDC_RETRIES: usize = 3;
+const DDC_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct MonitorInfo {
+    /// used in entity ids and commands
+    key: String,
+    name: String,
+    /// input source value -> name, taken from the capability string
+    inputs: Vec<(u8, String)>,
+}
+
+#[derive(serde::Serialize, Debug, Default)]
+struct MonitorState {
+    brightness: Option<u16>,
+    brightness_max: Option<u16>,
+    contrast: Option<u16>,
+    contrast_max: Option<u16>,
+    input_source: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct DdcState {
+    monitors: HashMap<String, MonitorState>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "type")]
+enum DdcCommand {
+    SetBrightness { monitor: String, value: u16 },
+    SetContrast { monitor: String, value: u16 },
+    SetInputSource { monitor: String, input: String },
+}
+
+enum DdcRequest {
+    Monitors(oneshot::Sender<Vec<MonitorInfo>>),
+    State(oneshot::Sender<DdcState>),
+    Write {
+        monitor: String,
+        feature: u8,
+        value: u16,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+struct Monitor {
+    info: MonitorInfo,
+    display: Display,
+}
+
+impl Monitor {
+    fn new(mut display: Display) -> Self {
+        // reading the capabilities takes seconds on some monitors, so they are only read once
+        let capabilities = match display.handle.capabilities() {
+            Ok(caps) => Some(caps),
+            Err(e) => {
+                log::warn!(
+                    "Could not read capabilities of display {}: {:?}",
+                    &display.info.id,
+                    e
+                );
+                None
+            }
+        };
+        if display.info.model_name.is_none() {
+            display.info.model_name = capabilities.as_ref().and_then(|caps| caps.model.clone());
+        }
+        let name = [&display.info.manufacturer_id, &display.info.model_name]
+            .iter()
+            .filter_map(|s| s.as_deref())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let key = [&display.info.model_name, &display.info.serial_number]
+            .iter()
+            .filter_map(|s| s.as_deref())
+            .collect::<Vec<&str>>()
+            .join("_");
+        let key = if key.is_empty() {
+            &display.info.id
+        } else {
+            &key
+        };
+        let key = entity_key(key);
+        let inputs = capabilities
+            .as_ref()
+            .and_then(|caps| caps.vcp_features.get(&VCP_INPUT_SOURCE))
+            .map(|descriptor| {
+                descriptor
+                    .values
+                    .iter()
+                    .map(|(value, name)| {
+                        let name = match name {
+                            Some(name) => name.clone(),
+                            None => input_value_to_string(*value).to_owned(),
+                        };
+                        (*value, name)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            info: MonitorInfo {
+                key,
+                name: if name.is_empty() {
+                    display.info.id.clone()
+                } else {
+                    name
+                },
+                inputs,
+            },
+            display,
+        }
+    }
+
+    fn read(&mut self, feature: u8) -> Option<(u16, u16)> {
+        for _ in 0..DDC_RETRIES {
+            match self.display.handle.get_vcp_feature(feature) {
+                Ok(value) => return Some((value.value(), value.maximum())),
+                Err(e) => {
+                    log::debug!(
+                        "Reading vcp {:#04x} of {} failed: {:?}",
+                        feature,
+                        &self.info.key,
+                        e
+                    );
+                    std::thread::sleep(DDC_RETRY_DELAY);
+                }
+            }
+        }
+        log::warn!("Could not read vcp {:#04x} of {}", feature, &self.info.key);
+        None
+    }
+
+    fn write(&mut self, feature: u8, value: u16) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for _ in 0..DDC_RETRIES {
+            result = self.display.handle.set_vcp_feature(feature, value);
+            if result.is_ok() {
+                break;
+            }
+            std::thread::sleep(DDC_RETRY_DELAY);
+        }
+        result
+    }
+
+    fn state(&mut self) -> MonitorState {
+        let brightness = self.read(VCP_BRIGHTNESS);
+        let contrast = self.read(VCP_CONTRAST);
+        let input_source = self.read(VCP_INPUT_SOURCE).map(|(value, _)| {
+            // the input source is only stored in the low byte
+            let value = (value & 0xff) as u8;
+            self.info
+                .inputs
+                .iter()
+                .find(|(v, _)| *v == value)
+                .map(|(_, name)| name.clone())
+                .unwrap_or_else(|| input_value_to_string(value).to_owned())
+        });
+        MonitorState {
+            brightness: brightness.map(|(v, _)| v),
+            brightness_max: brightness.map(|(_, max)| max),
+            contrast: contrast.map(|(v, _)| v),
+            contrast_max: contrast.map(|(_, max)| max),
+            input_source,
+        }
+    }
+}
+
+// DDC/CI is slow and blocking, so all access goes through this one thread which
+// handles the requests one after another
+fn ddc_worker(mut requests: mpsc::Receiver<DdcRequest>) {
+    let mut monitors: Vec<Monitor> = Vec::new();
+    while let Some(request) = requests.blocking_recv() {
+        match request {
+            DdcRequest::Monitors(reply) => {
+                // enumerate again every time, monitors might have been connected since
+                monitors = Display::enumerate().into_iter().map(Monitor::new).collect();
+                log::info!(
+                    "Found ddc monitors: {:?}",
+                    monitors.iter().map(|m| &m.info.name).collect::<Vec<_>>()
+                );
+                let _ = reply.send(monitors.iter().map(|m| m.info.clone()).collect());
+            }
+            DdcRequest::State(reply) => {
+                let monitors = monitors
+                    .iter_mut()
+                    .map(|m| (m.info.key.clone(), m.state()))
+                    .collect();
+                let _ = reply.send(DdcState { monitors });
+            }
+            DdcRequest::Write {
+                monitor,
+                feature,
+                value,
+                reply,
+            } => {
+                let result = match monitors.iter_mut().find(|m| m.info.key == monitor) {
+                    Some(m) => m.write(feature, value),
+                    None => Err(anyhow::anyhow!("unknown monitor {monitor}")),
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+/// handle to the ddc worker thread
+#[derive(Clone)]
+struct DdcQueue {
+    requests: mpsc::Sender<DdcRequest>,
+}
+
+impl DdcQueue {
+    fn start() -> Self {
+        let (requests, receiver) = mpsc::channel(16);
+        std::thread::spawn(move || ddc_worker(receiver));
+        Self { requests }
+    }
+    async fn monitors(&self) -> anyhow::Result<Vec<MonitorInfo>> {
+        let (reply, result) = oneshot::channel();
+        self.requests.send(DdcRequest::Monitors(reply)).await?;
+        Ok(result.await?)
+    }
+    async fn state(&self) -> anyhow::Result<DdcState> {
+        let (reply, result) = oneshot::channel();
+        self.requests.send(DdcRequest::State(reply)).await?;
+        Ok(result.await?)
+    }
+    async fn write(&self, monitor: String, feature: u8, value: u16) -> anyhow::Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .send(DdcRequest::Write {
+                monitor,
+                feature,
+                value,
+                reply,
+            })
+            .await?;
+        result.await?
+    }
+}
+
+// the ranges of the numbers are the maximums the monitors report with their state
+async fn autodiscover(
+    config: &Config,
+    client: &MqttClient,
+    monitors: &[MonitorInfo],
+    state: &DdcState,
+) {
+    for monitor in monitors {
+        let key = &monitor.key;
+        let monitor_state = state.monitors.get(key);
+        let features = [
+            (
+                "brightness",
+                "SetBrightness",
+                monitor_state.and_then(|s| s.brightness_max),
+            ),
+            (
+                "contrast",
+                "SetContrast",
+                monitor_state.and_then(|s| s.contrast_max),
+            ),
+        ];
+        for (feature, command, max) in features {
+            // most monitors go up to 100, then the value is a percentage
+            let max = max.unwrap_or(100);
+            let name = format!("{prefix}{key}_{feature}", prefix = &config.ddc.name_prefix);
+            let number = config.build_number(
+                config.ddc.command_topic.clone(),
+                config.ddc.state_topic.clone(),
+                config.ddc.availability.clone(),
+                name.clone(),
+                name,
+                (0.0, f64::from(max), 1.0),
+                (max == 100).then(|| "%".to_owned()),
+                format!("{{{{ value_json.monitors['{key}'].{feature} }}}}"),
+                format!(
+                    r#"{{"type": "{command}", "monitor": "{key}", "value": {{{{ value | int }}}}}}"#
+                ),
+            );
+            config.publish_autodiscover(client, &number).await;
+        }
+        if !monitor.inputs.is_empty() {
+            let name = format!("{prefix}{key}_input", prefix = &config.ddc.name_prefix);
+            let select = config.build_select(
+                monitor.inputs.iter().map(|(_, name)| name.clone()).collect(),
+                config.ddc.command_topic.clone(),
+                config.ddc.state_topic.clone(),
+                config.ddc.availability.clone(),
+                name.clone(),
+                name,
+                format!("{{{{ value_json.monitors['{key}'].input_source }}}}"),
+                Some(format!(
+                    r#"{{"type": "SetInputSource", "monitor": "{key}", "input": "{{{{ value }}}}"}}"#
+                )),
+                format!("{{{{ value_json.monitors['{key}'] | tojson }}}}"),
+            );
+            config.publish_autodiscover(client, &select).await;
+        }
+    }
+}
+
+async fn publish_state(
+    client: &MqttClient,
+    config: &Config,
+    state: &DdcState,
+) -> anyhow::Result<()> {
+    client
+        .publish(
+            &config.ddc.state_topic,
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_string(state)?,
+        )
+        .await?;
+    Ok(())
+}
+
+// ddc has no events, so the state is polled
+async fn ddc_state_task(
+    queue: &DdcQueue,
+    client: &MqttClient,
+    config: &Config,
+    monitors: &[MonitorInfo],
+    mut connects: watch::Receiver<u32>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.ddc.poll_interval.get()));
+    let mut discovered = false;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            // don't wait for the next poll to fill the state after a reconnect
+            Ok(()) = connects.changed() => {
+                discovered = false;
+            }
+        }
+        let state = match queue.state().await {
+            Ok(state) => state,
+            Err(e) => {
+                log::error!("Could not read ddc state: {:?}", e);
+                continue;
+            }
+        };
+        if !discovered {
+            autodiscover(config, client, monitors, &state).await;
+            discovered = true;
+        }
+        if let Err(e) = publish_state(client, config, &state).await {
+            log::error!("Could not publish ddc state: {:?}", e);
+        }
+    }
+}
+
+async fn run_command(
+    queue: &DdcQueue,
+    monitors: &[MonitorInfo],
+    command: DdcCommand,
+) -> anyhow::Result<()> {
+    match command {
+        DdcCommand::SetBrightness { monitor, value } => {
+            queue.write(monitor, VCP_BRIGHTNESS, value).await
+        }
+        DdcCommand::SetContrast { monitor, value } => {
+            queue.write(monitor, VCP_CONTRAST, value).await
+        }
+        DdcCommand::SetInputSource { monitor, input } => {
+            let value = monitors
+                .iter()
+                .find(|m| m.key == monitor)
+                .and_then(|m| m.inputs.iter().find(|(_, name)| *name == input))
+                .map(|(value, _)| *value)
+                .ok_or_else(|| anyhow::anyhow!("unknown input {input} for monitor {monitor}"))?;
+            queue.write(monitor, VCP_INPUT_SOURCE, value.into()).await
+        }
+    }
+}
+
+pub struct DdcModule {
+    config: Config,
+    queue: DdcQueue,
+    /// found when the module (re)starts
+    monitors: RwLock<Vec<MonitorInfo>>,
+}
+
+impl DdcModule {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            queue: DdcQueue::start(),
+            monitors: RwLock::new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Module for DdcModule {
+    fn name(&self) -> &str {
+        "ddc"
+    }
+
+    fn availability(&self) -> &Availability {
+        &self.config.ddc.availability
+    }
+
+    fn command_topics(&self) -> Vec<String> {
+        vec![self.config.ddc.command_topic.clone()]
+    }
+
+    async fn discovery(&self, _client: &MqttClient) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn state(
+        &self,
+        client: MqttClient,
+        connects: watch::Receiver<u32>,
+        mut ready: Ready,
+    ) -> anyhow::Result<()> {
+        let monitors = self.queue.monitors().await?;
+        if monitors.is_empty() {
+            anyhow::bail!("no monitor supports ddc/ci");
+        }
+        ready.connected();
+        *self.monitors.write().unwrap() = monitors.clone();
+        ddc_state_task(&self.queue, &client, &self.config, &monitors, connects).await;
+        Ok(())
+    }
+
+    async fn command(
+        &self,
+        client: &MqttClient,
+        _topic: &str,
+        payload: &str,
+    ) -> anyhow::Result<()> {
+        let ddc_command: DdcCommand = serde_json::from_str(payload)?;
+        log::debug!("Running ddc command: {:?}", &ddc_command);
+        let monitors = self.monitors.read().unwrap().clone();
+        run_command(&self.queue, &monitors, ddc_command).await?;
+        let state = self.queue.state().await?;
+        publish_state(client, &self.config, &state).await
+    }
+}
@@ -2,6 +2,15 @@
 use serde::Deserialize;
 use serde::Serialize;
 
+/// home assistant only accepts `[a-zA-Z0-9_-]` in object ids, names of sinks,
+/// monitors and scripts are mapped to that before they are used in ids or topics
+pub fn entity_key(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>()
+        .to_lowercase()
+}
+
 pub trait Component {
     fn component_str(&self) -> &str;
     fn object_id(&self) -> &str;
@@ -13,7 +22,8 @@ pub struct ComponentCommon {
     pub name: String,
     pub unique_id: String,
     pub device: Device,
-    pub availability: Availability,
+    pub availability: Vec<Availability>,
+    pub availability_mode: String,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -68,6 +78,8 @@ pub struct Select {
     pub common: ComponentCommon,
     pub options: Vec<String>,
     pub value_template: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_template: Option<String>,
     pub json_attributes_topic: String,
     pub json_attributes_template: String,
 }
@@ -84,3 +96,82 @@ impl Component for Select {
         serde_json::to_string(self).unwrap()
     }
 }
+
+#[derive(Serialize, Clone)]
+pub struct Number {
+    pub command_topic: String,
+    pub state_topic: String,
+    #[serde(flatten)]
+    pub common: ComponentCommon,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+    pub mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+    pub value_template: String,
+    pub command_template: String,
+}
+
+impl Component for Number {
+    fn component_str(&self) -> &str {
+        "number"
+    }
+    fn object_id(&self) -> &str {
+        &self.common.unique_id
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct Button {
+    pub command_topic: String,
+    #[serde(flatten)]
+    pub common: ComponentCommon,
+    pub payload_press: String,
+    pub json_attributes_topic: String,
+    pub json_attributes_template: String,
+}
+
+impl Component for Button {
+    fn component_str(&self) -> &str {
+        "button"
+    }
+    fn object_id(&self) -> &str {
+        &self.common.unique_id
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct BinarySensor {
+    pub state_topic: String,
+    #[serde(flatten)]
+    pub common: ComponentCommon,
+    pub value_template: String,
+    pub payload_on: String,
+    pub payload_off: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+    pub json_attributes_topic: String,
+    pub json_attributes_template: String,
+}
+
+impl Component for BinarySensor {
+    fn component_str(&self) -> &str {
+        "binary_sensor"
+    }
+    fn object_id(&self) -> &str {
+        &self.common.unique_id
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
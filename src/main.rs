@@ -1,13 +1,37 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::Parser;
 use log::log_enabled;
-use tokio::task;
 
+use crate::module::Module;
+
+mod camera;
 mod config;
+mod ddc;
 mod homeassistant;
+mod mccs;
+mod module;
+mod mqtt;
 mod pulseaudio;
+mod scripts;
 mod sway;
+mod wlr;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Control your PC from Home-Assistant (or any other MQTT hubs)"
+)]
+struct Args {
+    /// path to the config file, defaults to $XDG_CONFIG_HOME/desktop/config.yaml
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
 
 #[tokio::main(worker_threads = 1)]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     env_logger::init_from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
     );
@@ -18,14 +42,28 @@ async fn main() -> anyhow::Result<()> {
     if log_enabled!(log::Level::Error) {
         log::info!("Error logging enabled");
     }
-    let sway_handle = task::spawn(async move {
-        sway::sway_run().await.expect("sway_run");
-    });
-    let pulse_handle = task::spawn(async move {
-        pulseaudio::pulse_run().await.unwrap();
-    });
-    pulse_handle.await.unwrap();
-    sway_handle.await.unwrap();
+    let config = config::Config::load(args.config.as_deref())?;
+    let mut modules: Vec<Arc<dyn Module>> = Vec::new();
+    // sway has its own ipc, every other wlroots compositor is handled through the wayland protocols
+    if config.sway.enabled && std::env::var_os("SWAYSOCK").is_some() {
+        modules.push(Arc::new(sway::SwayModule::new(config.clone())));
+    } else if config.wlr.enabled {
+        modules.push(Arc::new(wlr::WlrModule::new(config.clone())));
+    }
+    if config.pulseaudio.enabled {
+        modules.push(Arc::new(pulseaudio::PulseModule::new(config.clone())));
+    }
+    if config.ddc.enabled {
+        modules.push(Arc::new(ddc::DdcModule::new(config.clone())));
+    }
+    if config.camera.enabled {
+        modules.push(Arc::new(camera::CameraModule::new(config.clone())));
+    }
+    if config.script_runner.enabled {
+        if let Some(scripts) = scripts::ScriptsModule::new(config.clone()) {
+            modules.push(Arc::new(scripts));
+        }
+    }
 
-    Ok(())
+    module::run(config, modules).await
 }
@@ -0,0 +1,27 @@
+//! Names from the MCCS standard that DDC/CI monitors use, shared with the ddc-test binary.
+
+/// input source values of vcp 0x60
+pub fn input_value_to_string(val: u8) -> &'static str {
+    match val {
+        0x01 => "VGA 1",
+        0x02 => "VGA 2",
+        0x03 => "DVI 1",
+        0x04 => "DVI 2",
+        0x05 => "Composite 1",
+        0x06 => "Composite 2",
+        0x07 => "S-Video 1",
+        0x08 => "S-Video 2",
+        0x09 => "Tuner 1",
+        0x0a => "Tuner 2",
+        0x0b => "Tuner 3",
+        0x0c => "Component 1",
+        0x0d => "Component 2",
+        0x0e => "Component 3",
+        0x0f => "DisplayPort 1",
+        0x10 => "DisplayPort 2",
+        0x11 => "HDMI 1",
+        0x12 => "HDMI 2",
+        0x1b => "USB-C",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,240 @@
+//! All integrations share one mqtt connection. The core in this file owns the client, routes
+//! incoming commands to the module that subscribed to the topic and republishes discovery,
+//! availability and subscriptions after every (re)connect.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, QoS};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task;
+
+use crate::config::Config;
+use crate::homeassistant::Availability;
+use crate::mqtt::{MqttConnection, MqttEvent};
+
+/// commands waiting for a module before new ones are dropped
+const COMMAND_QUEUE_SIZE: usize = 16;
+
+/// Handed to `state`, marks the module online once its backend is available.
+pub struct Ready(Option<oneshot::Sender<()>>);
+
+impl Ready {
+    /// Only the first call announces the module, so state loops can call it on every iteration.
+    pub fn connected(&mut self) {
+        if let Some(ready) = self.0.take() {
+            let _ = ready.send(());
+        }
+    }
+}
+
+/// An integration that exposes part of the desktop over mqtt.
+#[async_trait]
+pub trait Module: Send + Sync + 'static {
+    /// used in logs
+    fn name(&self) -> &str;
+
+    /// availability of the module's entities, they are only available if the device is too
+    fn availability(&self) -> &Availability;
+
+    /// topics the module receives commands on
+    fn command_topics(&self) -> Vec<String>;
+
+    /// Publishes the homeassistant discovery configs, called after every (re)connect.
+    /// Modules whose entities depend on the state, like the connected devices, publish them
+    /// from `state` instead, whenever the entities change.
+    async fn discovery(&self, client: &AsyncClient) -> anyhow::Result<()>;
+
+    /// Publishes the state whenever it changes. Returning means the module stopped working,
+    /// e.g. because its backend is not available, it is restarted after `retry_interval`.
+    /// `connects` changes after every reconnect, when the broker might have lost everything.
+    /// The entities stay unavailable until `ready` is marked connected.
+    async fn state(
+        &self,
+        client: AsyncClient,
+        connects: watch::Receiver<u32>,
+        ready: Ready,
+    ) -> anyhow::Result<()>;
+
+    /// Handles a message on one of the command topics. Commands of one module are handled
+    /// in order, but a slow module doesn't block the others.
+    async fn command(&self, client: &AsyncClient, topic: &str, payload: &str)
+        -> anyhow::Result<()>;
+}
+
+struct ModuleHandle {
+    module: Arc<dyn Module>,
+    /// false until the backend of the module is available, so reconnects don't mark it online
+    running: AtomicBool,
+}
+
+async fn publish_availability(
+    client: &AsyncClient,
+    config: &Config,
+    availability: &Availability,
+    available: bool,
+) -> anyhow::Result<()> {
+    let payload = if available {
+        &availability.payload_available
+    } else {
+        &availability.payload_not_available
+    };
+    client
+        .publish(
+            &availability.topic,
+            QoS::AtLeastOnce,
+            config.mqtt.retain_last_will,
+            payload.clone(),
+        )
+        .await?;
+    Ok(())
+}
+
+// publishes discovery, availability and subscriptions of one module
+async fn announce_module(
+    config: &Config,
+    client: &AsyncClient,
+    module: &dyn Module,
+) -> anyhow::Result<()> {
+    if let Err(e) = module.discovery(client).await {
+        log::error!("{}: could not publish discovery: {:?}", module.name(), e);
+    }
+    publish_availability(client, config, module.availability(), true).await?;
+    for topic in module.command_topics() {
+        client.subscribe(topic, QoS::AtLeastOnce).await?;
+    }
+    Ok(())
+}
+
+// publishes everything the broker needs after (re)connecting
+async fn announce(
+    config: &Config,
+    client: &AsyncClient,
+    modules: &[Arc<ModuleHandle>],
+) -> anyhow::Result<()> {
+    publish_availability(client, config, &config.availability, true).await?;
+    for handle in modules {
+        // modules that are waiting for their backend announce themselves once it is available
+        if handle.running.load(Ordering::Relaxed) {
+            announce_module(config, client, handle.module.as_ref()).await?;
+        }
+    }
+    Ok(())
+}
+
+// runs the state task of a module and restarts it whenever it stops
+async fn supervise(
+    config: Config,
+    client: AsyncClient,
+    handle: Arc<ModuleHandle>,
+    connects: watch::Receiver<u32>,
+) {
+    let module = handle.module.as_ref();
+    let retry_interval = Duration::from_secs(config.retry_interval);
+    loop {
+        let (ready, connected) = oneshot::channel();
+        let announce = async {
+            // the sender is dropped without sending if the module stops before it is ready
+            if connected.await.is_ok() {
+                handle.running.store(true, Ordering::Relaxed);
+                if let Err(e) = announce_module(&config, &client, module).await {
+                    log::error!("{}: could not announce module: {:?}", module.name(), e);
+                }
+            }
+        };
+        let (result, ()) = tokio::join!(
+            module.state(client.clone(), connects.clone(), Ready(Some(ready))),
+            announce
+        );
+        log::error!(
+            "{}: module stopped, retrying in {:?}: {:?}",
+            module.name(),
+            retry_interval,
+            &result
+        );
+        handle.running.store(false, Ordering::Relaxed);
+        // nothing updates the entities anymore
+        if let Err(e) = publish_availability(&client, &config, module.availability(), false).await {
+            log::error!("{}: could not publish availability: {:?}", module.name(), e);
+        }
+        tokio::time::sleep(retry_interval).await;
+        log::info!("{}: restarting module", module.name());
+    }
+}
+
+/// Runs the modules on one mqtt connection, only returns if the connection can't be set up.
+pub async fn run(config: Config, modules: Vec<Arc<dyn Module>>) -> anyhow::Result<()> {
+    let (client, eventloop) = config.get_client();
+    let mut mqtt = MqttConnection::new(&config.mqtt.client_id, eventloop);
+
+    let mut routes: HashMap<String, mpsc::Sender<(String, String)>> = HashMap::new();
+    let mut handles = Vec::new();
+    for module in modules {
+        log::info!("Starting {} module", module.name());
+        let handle = Arc::new(ModuleHandle {
+            module: module.clone(),
+            running: AtomicBool::new(false),
+        });
+
+        let (commands, mut commands_receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        for topic in module.command_topics() {
+            routes.insert(topic, commands.clone());
+        }
+        let (module_command, client_command) = (module.clone(), client.clone());
+        task::spawn(async move {
+            while let Some((topic, payload)) = commands_receiver.recv().await {
+                let result = module_command
+                    .command(&client_command, &topic, &payload)
+                    .await;
+                if let Err(e) = result {
+                    log::error!(
+                        "{}: error running command {:?}: {:?}",
+                        module_command.name(),
+                        &payload,
+                        e
+                    );
+                }
+            }
+        });
+
+        task::spawn(supervise(
+            config.clone(),
+            client.clone(),
+            handle.clone(),
+            mqtt.connects(),
+        ));
+        handles.push(handle);
+    }
+
+    log::info!("Starting mqtt command loop");
+    loop {
+        let message = match mqtt.poll().await {
+            MqttEvent::Connected => {
+                // the publishes only go through while the eventloop is polled, so announce in
+                // the background, there are more messages than fit in the request queue
+                let (config, client, handles) = (config.clone(), client.clone(), handles.clone());
+                task::spawn(async move {
+                    if let Err(e) = announce(&config, &client, &handles).await {
+                        log::error!("Could not announce modules: {:?}", e);
+                    }
+                });
+                continue;
+            }
+            MqttEvent::Publish(message) => message,
+        };
+        let Some(commands) = routes.get(&message.topic) else {
+            log::error!("Received message on unknown topic {}", &message.topic);
+            continue;
+        };
+        let Ok(payload) = String::from_utf8(message.payload.to_vec()) else {
+            log::error!("Received invalid utf8 string from mqtt");
+            continue;
+        };
+        // never wait here, the module might be waiting for a publish that needs the eventloop
+        if let Err(e) = commands.try_send((message.topic, payload)) {
+            log::error!("Dropping command, the module is busy: {:?}", e);
+        }
+    }
+}
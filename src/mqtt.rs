@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use rumqttc::{Event, EventLoop, Packet, Publish};
+use tokio::sync::watch;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub enum MqttEvent {
+    /// (re)connected to the broker, subscriptions, discovery and availability need to be published again
+    Connected,
+    Publish(Publish),
+}
+
+/// Wraps the rumqttc eventloop to keep reconnecting with exponential backoff instead of
+/// giving up on the first error.
+pub struct MqttConnection {
+    name: String,
+    eventloop: EventLoop,
+    backoff: Duration,
+    connects: watch::Sender<u32>,
+}
+
+impl MqttConnection {
+    pub fn new(name: &str, eventloop: EventLoop) -> Self {
+        Self {
+            name: name.to_owned(),
+            eventloop,
+            backoff: INITIAL_BACKOFF,
+            connects: watch::channel(0).0,
+        }
+    }
+
+    /// Changes every time the connection is (re)established, so background tasks can
+    /// republish what the broker might have lost.
+    pub fn connects(&self) -> watch::Receiver<u32> {
+        self.connects.subscribe()
+    }
+
+    pub async fn poll(&mut self) -> MqttEvent {
+        loop {
+            match self.eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    self.backoff = INITIAL_BACKOFF;
+                    self.connects.send_modify(|c| *c += 1);
+                    let connects = *self.connects.borrow();
+                    if connects > 1 {
+                        log::info!(
+                            "{}: reconnected to mqtt broker (reconnect #{})",
+                            &self.name,
+                            connects - 1
+                        );
+                    } else {
+                        log::info!("{}: connected to mqtt broker", &self.name);
+                    }
+                    return MqttEvent::Connected;
+                }
+                Ok(Event::Incoming(Packet::Publish(message))) => {
+                    return MqttEvent::Publish(message);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!(
+                        "{}: mqtt connection error: {:?}, retrying in {:?}",
+                        &self.name,
+                        e,
+                        self.backoff
+                    );
+                    tokio::time::sleep(self.backoff).await;
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
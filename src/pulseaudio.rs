@@ -1,86 +1,1217 @@
-use tokio::task;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::NaiveTime;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
 use futures_util::{pin_mut, stream::StreamExt};
 use rumqttc::{self, AsyncClient, QoS};
 
-use pulsectl::Pulseaudio;
+use pulsectl::{Pulseaudio, SinkInfo, VolumeLevel};
 
 const CLIENT_NAME_CMD: &str = "desktop-cmd";
 const CLIENT_NAME_STATE: &str = "desktop-state";
+/// how long events are collected before the state is fetched
+const UPDATE_DEBOUNCE: Duration = Duration::from_millis(100);
+/// the server resolves these to the current default devices
+const DEFAULT_SINK: &str = "@DEFAULT_SINK@";
+const DEFAULT_SOURCE: &str = "@DEFAULT_SOURCE@";
+/// time between two volume changes of a fade
+const RAMP_STEP: Duration = Duration::from_millis(50);
+
+use crate::config::{wildcard_match, AudioPresetConfig, Config, PresetCardProfileConfig};
+use crate::homeassistant::{entity_key, Availability, Component};
+use crate::module::{Module, Ready};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "type")]
+enum PulseCommand {
+    SetDefaultSink {
+        sink_name: String,
+    },
+    VolumeUp {
+        step: u8,
+    },
+    VolumeDown {
+        step: u8,
+    },
+    SetVolume {
+        percent: u8,
+    },
+    SetMute {
+        mute: bool,
+    },
+    /// exact volume of a sink, the default sink if no name is given
+    SetSinkVolume {
+        sink_name: Option<String>,
+        volume: pulsectl::VolumeLevel,
+    },
+    SetChannelVolume {
+        sink_name: Option<String>,
+        channel: String,
+        volume: pulsectl::VolumeLevel,
+    },
+    /// -1.0 (left) to 1.0 (right)
+    SetBalance {
+        sink_name: Option<String>,
+        balance: f64,
+    },
+    ToggleMute,
+    CycleSinks,
+    SetDefaultSource {
+        source_name: String,
+    },
+    SetSourceMute {
+        mute: bool,
+    },
+    SetSinkInputVolume {
+        index: u32,
+        percent: u8,
+    },
+    SetSinkInputMute {
+        index: u32,
+        mute: bool,
+    },
+    MoveSinkInput {
+        index: u32,
+        sink_name: String,
+    },
+    SetCardProfile {
+        card_name: String,
+        profile: String,
+    },
+    ApplyPreset {
+        preset_name: String,
+    },
+}
 
-use crate::config::Config;
+#[derive(serde::Serialize, Debug)]
+struct PulseError<'a> {
+    command: &'a str,
+    error: String,
+}
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct PulseState {
+    /// only the sinks allowed by the config
     sinks: Vec<pulsectl::SinkInfo>,
     current_sink: String,
+    /// alias or description of the current sink, shown by the select
+    current_sink_display_name: String,
+    /// sink name -> display name, unique over all sinks
+    #[serde(skip)]
+    sink_display_names: BTreeMap<String, String>,
     current_volume: String,
+    current_mute: bool,
+    /// sources without the monitors of the sinks
+    sources: Vec<pulsectl::SourceInfo>,
+    current_source: Option<String>,
+    current_source_mute: Option<bool>,
+    /// what the applications are playing
+    streams: Vec<StreamState>,
+    /// by card key
+    cards: BTreeMap<String, CardState>,
+    /// sink key -> whether the sink is running
+    sinks_playing: BTreeMap<String, bool>,
+    /// any sink is running
+    playing: bool,
+    /// applications recording from a microphone, monitors of sinks don't count
+    recording_applications: Vec<String>,
+    recording: bool,
+    /// preset that matches the current state
+    current_preset: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct StreamState {
+    /// used by the sink input commands
+    index: u32,
+    application: Option<String>,
+    binary: Option<String>,
+    media: Option<String>,
+    sink: Option<String>,
+    volume: Option<String>,
+    mute: bool,
+    corked: bool,
+}
+
+impl StreamState {
+    fn new(input: &pulsectl::SinkInputInfo, sinks: &[pulsectl::SinkInfo]) -> Self {
+        Self {
+            index: input.index,
+            application: input.application_name().map(str::to_owned),
+            binary: input.binary().map(str::to_owned),
+            media: input.media_name().map(str::to_owned),
+            sink: sinks
+                .iter()
+                .find(|s| s.index == input.sink)
+                .map(|s| s.name.clone()),
+            // the channels of a stream are usually at the same volume
+            volume: input
+                .channel_map
+                .split(',')
+                .next()
+                .and_then(|channel| input.volume.get(channel))
+                .map(|v| v.value_percent.clone()),
+            mute: input.mute,
+            corked: input.corked,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct CardState {
+    name: String,
+    description: Option<String>,
+    active_profile: Option<String>,
+    /// profiles that can be activated, the preferred ones first
+    profiles: Vec<String>,
+}
+
+/// what the discovery configs depend on, they are republished when it changes
+#[derive(Debug, PartialEq)]
+struct Entities {
+    /// display names of the allowed sinks and the current one
+    sinks: Vec<String>,
+    /// key -> name of the allowed sinks, for the playing sensors
+    sink_keys: BTreeMap<String, String>,
+    sources: Vec<String>,
+    /// key -> card, without the active profile that only changes the state
+    cards: BTreeMap<String, CardEntity>,
+}
+
+#[derive(Debug, PartialEq)]
+struct CardEntity {
+    name: String,
+    profiles: Vec<String>,
 }
 
-pub async fn pulse_state(client: AsyncClient, config: &Config) -> anyhow::Result<()> {
+impl Entities {
+    fn new(state: &PulseState) -> Self {
+        Self {
+            sinks: state
+                .sinks
+                .iter()
+                .filter_map(|s| state.sink_display_names.get(&s.name).cloned())
+                .collect(),
+            sink_keys: state
+                .sinks
+                .iter()
+                .map(|s| (entity_key(&s.name), s.name.clone()))
+                .collect(),
+            sources: state.sources.iter().map(|s| s.name.clone()).collect(),
+            cards: state
+                .cards
+                .iter()
+                .map(|(key, card)| {
+                    let card = CardEntity {
+                        name: card.name.clone(),
+                        profiles: card.profiles.clone(),
+                    };
+                    (key.clone(), card)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// publishes discovery configs and remembers their topics
+struct Discovery<'a> {
+    config: &'a Config,
+    client: &'a AsyncClient,
+    topics: BTreeSet<String>,
+}
+
+impl Discovery<'_> {
+    async fn publish(&mut self, component: &impl Component) {
+        self.topics
+            .insert(self.config.get_autodiscover_topic(component));
+        self.config
+            .publish_autodiscover(self.client, component)
+            .await;
+    }
+}
+
+/// returns the config topics of the entities, entities that are gone have to be removed
+async fn autodiscover(
+    config: &Config,
+    client: &AsyncClient,
+    entities: &Entities,
+) -> BTreeSet<String> {
+    let prefix = &config.pulseaudio.name_prefix;
+    let mut discovery = Discovery {
+        config,
+        client,
+        topics: BTreeSet::new(),
+    };
+    {
+        // default sink
+        let name = format!("{prefix}default_sink");
+        let select = config.build_select(
+            entities.sinks.clone(),
+            config.pulseaudio.command_topic.clone(),
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            "{{ value_json.current_sink_display_name }}".to_owned(),
+            Some(r#"{"type": "SetDefaultSink", "sink_name": "{{ value }}"}"#.to_owned()),
+            "{{ {'sinks': value_json.sinks | map(attribute='name') | list} | tojson }}".to_owned(),
+        );
+        discovery.publish(&select).await;
+    }
+    {
+        // volume of the default sink
+        let name = format!("{prefix}volume");
+        let number = config.build_number(
+            config.pulseaudio.command_topic.clone(),
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            (
+                0.0,
+                f64::from(config.pulseaudio.highest_volume_limit()),
+                1.0,
+            ),
+            Some("%".to_owned()),
+            "{{ value_json.current_volume | replace('%', '') | int }}".to_owned(),
+            r#"{"type": "SetVolume", "percent": {{ value | int }}}"#.to_owned(),
+        );
+        discovery.publish(&number).await;
+    }
+    {
+        // mute of the default sink
+        let name = format!("{prefix}mute");
+        let switch = config.build_switch(
+            config.pulseaudio.command_topic.clone(),
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            format!(
+                "{{{{ '{on}' if value_json.current_mute == true else '{off}' }}}}",
+                on = &config.switch_on_value,
+                off = &config.switch_off_value,
+            ),
+            "{{ {'sink': value_json.current_sink} | tojson }}".to_owned(),
+            serde_json::to_string(&PulseCommand::SetMute { mute: true }).unwrap(),
+            serde_json::to_string(&PulseCommand::SetMute { mute: false }).unwrap(),
+            config.pulseaudio.state_topic.clone(),
+        );
+        discovery.publish(&switch).await;
+    }
+    {
+        // any sink is playing
+        let name = format!("{prefix}playing");
+        let sensor = config.build_binary_sensor(
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            format!(
+                "{{{{ '{on}' if value_json.playing == true else '{off}' }}}}",
+                on = &config.switch_on_value,
+                off = &config.switch_off_value,
+            ),
+            Some("sound".to_owned()),
+            "{{ {'sinks_playing': value_json.sinks_playing} | tojson }}".to_owned(),
+        );
+        discovery.publish(&sensor).await;
+    }
+    {
+        // an application records from a microphone
+        let name = format!("{prefix}mic_in_use");
+        let sensor = config.build_binary_sensor(
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            format!(
+                "{{{{ '{on}' if value_json.recording == true else '{off}' }}}}",
+                on = &config.switch_on_value,
+                off = &config.switch_off_value,
+            ),
+            None,
+            "{{ {'applications': value_json.recording_applications} | tojson }}".to_owned(),
+        );
+        discovery.publish(&sensor).await;
+    }
+    for (key, sink) in &entities.sink_keys {
+        let name = format!("{prefix}{key}_playing");
+        let sensor = config.build_binary_sensor(
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            format!(
+                "{{{{ '{on}' if value_json.sinks_playing['{key}'] == true else '{off}' }}}}",
+                on = &config.switch_on_value,
+                off = &config.switch_off_value,
+            ),
+            Some("sound".to_owned()),
+            format!(
+                "{{{{ {{'streams': value_json.streams | selectattr('sink', 'eq', '{sink}') | list}} | tojson }}}}"
+            ),
+        );
+        discovery.publish(&sensor).await;
+    }
+    if !config.pulseaudio.presets.is_empty() {
+        // applies sink, volume, mute and card profile of a preset at once
+        let name = format!("{prefix}preset");
+        let select = config.build_select(
+            config
+                .pulseaudio
+                .presets
+                .iter()
+                .map(|p| p.name.clone())
+                .collect(),
+            config.pulseaudio.command_topic.clone(),
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            "{{ value_json.current_preset or '' }}".to_owned(),
+            Some(r#"{"type": "ApplyPreset", "preset_name": "{{ value }}"}"#.to_owned()),
+            "{{ {'current_preset': value_json.current_preset} | tojson }}".to_owned(),
+        );
+        discovery.publish(&select).await;
+    }
+    for (key, card) in &entities.cards {
+        // profile of the card, e.g. a2dp or headset mode of bluetooth headphones
+        let name = format!("{prefix}{key}_profile");
+        let select = config.build_select(
+            card.profiles.clone(),
+            config.pulseaudio.command_topic.clone(),
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            format!("{{{{ value_json.cards['{key}'].active_profile }}}}"),
+            Some(format!(
+                r#"{{"type": "SetCardProfile", "card_name": "{card_name}", "profile": "{{{{ value }}}}"}}"#,
+                card_name = &card.name
+            )),
+            format!("{{{{ value_json.cards['{key}'] | tojson }}}}"),
+        );
+        discovery.publish(&select).await;
+    }
+    if entities.sources.is_empty() {
+        // no microphone, nothing to select or mute
+        return discovery.topics;
+    }
+    {
+        // default source
+        let name = format!("{prefix}default_source");
+        let select = config.build_select(
+            entities.sources.clone(),
+            config.pulseaudio.command_topic.clone(),
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            "{{ value_json.current_source }}".to_owned(),
+            Some(r#"{"type": "SetDefaultSource", "source_name": "{{ value }}"}"#.to_owned()),
+            "{{ {'sources': value_json.sources | map(attribute='name') | list} | tojson }}"
+                .to_owned(),
+        );
+        discovery.publish(&select).await;
+    }
+    {
+        // mute of the default source
+        let name = format!("{prefix}mic_mute");
+        let switch = config.build_switch(
+            config.pulseaudio.command_topic.clone(),
+            config.pulseaudio.state_topic.clone(),
+            config.pulseaudio.availability.clone(),
+            name.clone(),
+            name,
+            format!(
+                "{{{{ '{on}' if value_json.current_source_mute == true else '{off}' }}}}",
+                on = &config.switch_on_value,
+                off = &config.switch_off_value,
+            ),
+            "{{ {'source': value_json.current_source} | tojson }}".to_owned(),
+            serde_json::to_string(&PulseCommand::SetSourceMute { mute: true }).unwrap(),
+            serde_json::to_string(&PulseCommand::SetSourceMute { mute: false }).unwrap(),
+            config.pulseaudio.state_topic.clone(),
+        );
+        discovery.publish(&switch).await;
+    }
+    discovery.topics
+}
+
+/// one snapshot of everything, every list is only fetched once
+async fn get_state(config: &Config, pulse: &Pulseaudio) -> Option<PulseState> {
+    let Ok(server) = pulse.server_info().await else {
+        log::error!("Failed to get server info");
+        return None;
+    };
+    let Ok(sinks) = pulse.list_sinks().await else {
+        log::error!("Failed to get sinks");
+        return None;
+    };
+    let Some(current_sink) = sinks
+        .iter()
+        .find(|s| s.name == server.default_sink_name)
+        .cloned()
+    else {
+        log::error!("Failed to get default sink");
+        return None;
+    };
+    // a desktop without a microphone still has sinks to control
+    let sources = match pulse.list_sources().await {
+        Ok(sources) => sources,
+        Err(e) => {
+            log::error!("Failed to get sources: {:?}", e);
+            Vec::new()
+        }
+    };
+    let current_source = sources
+        .iter()
+        .find(|s| s.name == server.default_source_name)
+        .cloned();
+    let recording_applications: Vec<String> = match pulse.list_source_outputs().await {
+        Ok(outputs) => outputs
+            .iter()
+            .filter(|o| {
+                sources
+                    .iter()
+                    .any(|s| s.index == o.source && !s.is_monitor())
+            })
+            .map(|o| o.application_name().unwrap_or("unknown").to_owned())
+            .collect(),
+        Err(e) => {
+            log::error!("Failed to get source outputs: {:?}", e);
+            Vec::new()
+        }
+    };
+    let sources = sources.into_iter().filter(|s| !s.is_monitor()).collect();
+    let streams = match pulse.list_sink_inputs().await {
+        Ok(inputs) => inputs.iter().map(|i| StreamState::new(i, &sinks)).collect(),
+        Err(e) => {
+            log::error!("Failed to get sink inputs: {:?}", e);
+            Vec::new()
+        }
+    };
+    let cards = match pulse.list_cards().await {
+        Ok(cards) => cards
+            .into_iter()
+            .map(|card| {
+                let state = CardState {
+                    description: card.description().map(str::to_owned),
+                    active_profile: card.active_profile.clone(),
+                    profiles: card.available_profiles(),
+                    name: card.name,
+                };
+                (entity_key(&state.name), state)
+            })
+            .collect(),
+        Err(e) => {
+            log::error!("Failed to get cards: {:?}", e);
+            BTreeMap::new()
+        }
+    };
+    let sink_display_names: BTreeMap<String, String> = sinks
+        .iter()
+        .map(|s| s.name.clone())
+        .zip(config.pulseaudio.sink_display_names(&sinks))
+        .collect();
+    // the current sink is always an option, otherwise homeassistant rejects the select's state
+    let sinks: Vec<pulsectl::SinkInfo> = sinks
+        .into_iter()
+        .filter(|s| s.name == current_sink.name || config.pulseaudio.sink_allowed(&s.name))
+        .collect();
+    let sinks_playing: BTreeMap<String, bool> = sinks
+        .iter()
+        .map(|s| (entity_key(&s.name), s.state == "RUNNING"))
+        .collect();
+    let mut state = PulseState {
+        current_sink_display_name: sink_display_names[&current_sink.name].clone(),
+        sink_display_names,
+        current_volume: current_sink.max_volume().value_percent,
+        current_sink: current_sink.name,
+        current_mute: current_sink.mute,
+        sinks,
+        sources,
+        current_source_mute: current_source.as_ref().map(|s| s.mute),
+        current_source: current_source.map(|s| s.name),
+        streams,
+        cards,
+        playing: sinks_playing.values().any(|playing| *playing),
+        sinks_playing,
+        recording: !recording_applications.is_empty(),
+        recording_applications,
+        current_preset: None,
+    };
+    state.current_preset = matching_preset(config, &state).map(|p| p.name.clone());
+    Some(state)
+}
+
+/// The first preset whose values are all set, values the preset leaves out are ignored.
+fn matching_preset<'a>(config: &'a Config, state: &PulseState) -> Option<&'a AudioPresetConfig> {
+    // applying the preset limits its volume, so the limited volume is what the sink has
+    let volume_matches = |volume: u8| {
+        let volume = limit_volume(
+            config,
+            &state.current_sink,
+            VolumeLevel::Percent(f64::from(volume)),
+        );
+        pulsectl::Volume::from_raw(volume.to_raw()).value_percent == state.current_volume
+    };
+    config.pulseaudio.presets.iter().find(|preset| {
+        let card_matches = |p: &PresetCardProfileConfig| {
+            state.cards.values().any(|card| {
+                wildcard_match(&p.card, &card.name)
+                    && card.active_profile.as_ref() == Some(&p.profile)
+            })
+        };
+        wildcard_match(&preset.sink, &state.current_sink)
+            && preset.volume.is_none_or(volume_matches)
+            && (preset.mute.is_none() || preset.mute == Some(state.current_mute))
+            && preset.card_profile.iter().all(card_matches)
+    })
+}
+
+async fn apply_preset(
+    config: &Config,
+    pulse: &Pulseaudio,
+    preset: &AudioPresetConfig,
+) -> Result<(), pulsectl::Error> {
+    log::info!("Applying audio preset {}", &preset.name);
+    // first, the sink might only exist with the right profile
+    if let Some(card_profile) = &preset.card_profile {
+        let card = pulse
+            .list_cards()
+            .await?
+            .into_iter()
+            .find(|c| wildcard_match(&card_profile.card, &c.name))
+            .ok_or_else(|| {
+                pulsectl::Error::PulseError(format!("no card matches {}", &card_profile.card))
+            })?;
+        if card.active_profile.as_ref() != Some(&card_profile.profile) {
+            pulse.set_card_profile(&card, &card_profile.profile).await?;
+        }
+    }
+    let sink = pulse
+        .list_sinks()
+        .await?
+        .into_iter()
+        .find(|s| wildcard_match(&preset.sink, &s.name))
+        .ok_or_else(|| pulsectl::Error::PulseError(format!("no sink matches {}", &preset.sink)))?;
+    pulse.set_default_sink(&sink).await?;
+    if let Some(volume) = preset.volume {
+        let volume = limit_volume(config, &sink.name, VolumeLevel::Percent(f64::from(volume)));
+        pulse.set_sink_volume(&sink.name, volume).await?;
+    }
+    if let Some(mute) = preset.mute {
+        pulse.set_mute(mute).await?;
+    }
+    Ok(())
+}
+
+async fn pulse_state(
+    client: AsyncClient,
+    config: &Config,
+    mut connects: watch::Receiver<u32>,
+    mut ready: Ready,
+) -> anyhow::Result<()> {
     log::info!("Starting pulseaudio state task");
 
     let pulse = Pulseaudio::new(CLIENT_NAME_STATE);
-    let stream = pulse.subscribe().await;
+    // fails if pactl is not installed or no server is running
+    pulse
+        .server_info()
+        .await
+        .map_err(|e| anyhow::anyhow!("pulseaudio is not available: {:?}", e))?;
+    let stream = pulse
+        .subscribe()
+        .await
+        .map_err(|e| anyhow::anyhow!("could not subscribe to pulseaudio: {:?}", e))?;
     pin_mut!(stream);
-    while let Some(s) = stream.next().await {
-        if matches!(s.target, pulsectl::EventTarget::Sink) {
-            log::debug!("Got pulseaudio sink event: {:?}", &s);
-            let Ok(sinks) = pulse.list_sinks().await else {
-                log::error!("Failed to get sinks");
-                continue;
+    ready.connected();
+    // entities of the last published discovery, to republish it when devices are added or removed
+    let mut discovered: Option<Entities> = None;
+    // config topics of the last published discovery, kept over reconnects to remove what's gone
+    let mut announced: BTreeSet<String> = BTreeSet::new();
+    // the last published state, unchanged states aren't published again
+    let mut published: Option<String> = None;
+    // when to fetch the next snapshot, events until then are part of the same burst
+    let mut update = Some(Instant::now());
+    let mut last_error = None;
+    loop {
+        tokio::select! {
+            event = stream.next() => {
+                let s = match event {
+                    Some(Ok(s)) => s,
+                    Some(Err(e)) => {
+                        // the stream ends after errors that can't be recovered from
+                        log::error!("Error in pulseaudio event stream: {:?}", &e);
+                        last_error = Some(e);
+                        continue;
+                    }
+                    None => break,
+                };
+                // changing the default sink or source is a server event
+                let relevant = matches!(
+                    s.target,
+                    pulsectl::EventTarget::Sink
+                        | pulsectl::EventTarget::Source
+                        | pulsectl::EventTarget::SinkInput
+                        | pulsectl::EventTarget::SourceOutput
+                        | pulsectl::EventTarget::Card
+                        | pulsectl::EventTarget::Server
+                );
+                if relevant {
+                    log::debug!("Got pulseaudio event: {:?}", &s);
+                    // e.g. dragging a volume slider sends dozens of events
+                    update.get_or_insert_with(|| Instant::now() + UPDATE_DEBOUNCE);
+                }
+            }
+            Ok(()) = connects.changed() => {
+                // the broker might have lost the discovery configs and the state
+                discovered = None;
+                published = None;
+                update = Some(Instant::now());
+            }
+            _ = tokio::time::sleep_until(update.unwrap_or_else(Instant::now)), if update.is_some() => {
+                update = None;
+                let Some(state) = get_state(config, &pulse).await else {
+                    continue;
+                };
+                let entities = Entities::new(&state);
+                if discovered.as_ref() != Some(&entities) {
+                    log::info!("Devices changed, publishing autodiscover: {:?}", &entities);
+                    let topics = autodiscover(config, &client, &entities).await;
+                    // e.g. a bluetooth headset disconnected, its entities would stay unavailable
+                    for topic in announced.difference(&topics) {
+                        config.remove_autodiscover(&client, topic).await;
+                    }
+                    announced = topics;
+                    discovered = Some(entities);
+                }
+                let payload = serde_json::to_string(&state)?;
+                if published.as_ref() == Some(&payload) {
+                    continue;
+                }
+                log::debug!(
+                    "Publishing new state {} to {}",
+                    &payload,
+                    &config.pulseaudio.state_topic
+                );
+                // retained, so homeassistant has the state right after it restarts
+                client
+                    .publish(
+                        &config.pulseaudio.state_topic,
+                        QoS::AtLeastOnce,
+                        true,
+                        payload.clone(),
+                    )
+                    .await?;
+                published = Some(payload);
+            }
+        }
+    }
+    Err(anyhow::anyhow!(
+        "pulseaudio event stream ended: {:?}",
+        last_error
+    ))
+}
+/// finds a sink by its name or the name shown in homeassistant
+async fn find_sink(
+    config: &Config,
+    pulse: &Pulseaudio,
+    name: &str,
+) -> Result<pulsectl::SinkInfo, pulsectl::Error> {
+    let sinks = pulse.list_sinks().await?;
+    let display_names = config.pulseaudio.sink_display_names(&sinks);
+    sinks
+        .into_iter()
+        .zip(display_names)
+        .find(|(s, display_name)| s.name == name || display_name == name)
+        .map(|(s, _)| s)
+        .ok_or_else(|| pulsectl::Error::PulseError(format!("sink {name} does not exist")))
+}
+
+/// the loudest the sink may be set to right now, in percent
+fn volume_limit(config: &Config, sink_name: &str) -> u8 {
+    config
+        .pulseaudio
+        .volume_limit(sink_name, chrono::Local::now().time())
+}
+
+fn limit_volume(config: &Config, sink_name: &str, volume: VolumeLevel) -> VolumeLevel {
+    let limit = VolumeLevel::Percent(f64::from(volume_limit(config, sink_name)));
+    if volume.to_raw() > limit.to_raw() {
+        // debug, the state task checks the presets with every update
+        log::debug!("Limiting volume of {} to {:?}", sink_name, limit);
+        limit
+    } else {
+        volume
+    }
+}
+
+/// the channel volumes with the loudest one at `max`, the balance stays as it is
+fn scale_volumes(volumes: &[u32], max: u32) -> Vec<u32> {
+    let loudest = volumes.iter().copied().max().unwrap_or(0);
+    if loudest == 0 {
+        return vec![max; volumes.len()];
+    }
+    volumes
+        .iter()
+        .map(|v| (u64::from(*v) * u64::from(max) / u64::from(loudest)) as u32)
+        .collect()
+}
+
+/// The channel volumes after `VolumeUp`, pactl and libpulse let the volume grow past 100%
+/// so the step ends at the limit. None if the sink is already at or above it.
+fn raised_volumes(
+    config: &Config,
+    sink_name: &str,
+    volumes: &[u32],
+    step: u8,
+    time: NaiveTime,
+) -> Option<Vec<u32>> {
+    let limit =
+        VolumeLevel::Percent(f64::from(config.pulseaudio.volume_limit(sink_name, time))).to_raw();
+    let current = volumes.iter().copied().max().unwrap_or(0);
+    let step = VolumeLevel::Percent(f64::from(step)).to_raw();
+    let target = current.saturating_add(step).min(limit);
+    (target > current).then(|| scale_volumes(volumes, target))
+}
+
+async fn volume_up(config: &Config, pulse: &Pulseaudio, step: u8) -> Result<(), pulsectl::Error> {
+    let sink = pulse.get_sink(DEFAULT_SINK).await?;
+    let volumes = sink.channel_volumes();
+    match raised_volumes(
+        config,
+        &sink.name,
+        &volumes,
+        step,
+        chrono::Local::now().time(),
+    ) {
+        Some(volumes) => pulse.set_sink_channel_volumes(&sink.name, &volumes).await,
+        None => {
+            log::info!("{} is at its volume limit", &sink.name);
+            Ok(())
+        }
+    }
+}
+
+/// Sets the loudest channel of the sink to `percent`, in `volume_ramp_ms` when a fade is
+/// configured. Scaling the channels keeps the balance.
+async fn fade_volume(
+    config: &Config,
+    pulse: &Pulseaudio,
+    sink: &SinkInfo,
+    percent: u8,
+) -> Result<(), pulsectl::Error> {
+    let volumes = sink.channel_volumes();
+    let from = i64::from(volumes.iter().copied().max().unwrap_or(0));
+    let to = i64::from(VolumeLevel::Percent(f64::from(percent)).to_raw());
+    let steps = (config.pulseaudio.volume_ramp_ms / RAMP_STEP.as_millis() as u64).max(1) as i64;
+    for i in 1..=steps {
+        let max = from + (to - from) * i / steps;
+        pulse
+            .set_sink_channel_volumes(&sink.name, &scale_volumes(&volumes, max as u32))
+            .await?;
+        if i < steps {
+            tokio::time::sleep(RAMP_STEP).await;
+        }
+    }
+    Ok(())
+}
+
+/// The running volume fade. It runs next to the other commands, which would otherwise wait
+/// for it, and is aborted by the next command that changes a volume.
+#[derive(Default)]
+struct Fade(std::sync::Mutex<Option<JoinHandle<()>>>);
+
+impl Fade {
+    fn abort(&self) {
+        if let Some(fade) = self.0.lock().unwrap().take() {
+            fade.abort();
+        }
+    }
+
+    fn start(&self, fade: impl std::future::Future<Output = ()> + Send + 'static) {
+        let mut running = self.0.lock().unwrap();
+        if let Some(old) = running.take() {
+            old.abort();
+        }
+        *running = Some(tokio::spawn(fade));
+    }
+}
+
+impl PulseCommand {
+    fn changes_volume(&self) -> bool {
+        matches!(
+            self,
+            PulseCommand::VolumeUp { .. }
+                | PulseCommand::VolumeDown { .. }
+                | PulseCommand::SetVolume { .. }
+                | PulseCommand::SetSinkVolume { .. }
+                | PulseCommand::SetChannelVolume { .. }
+                | PulseCommand::SetBalance { .. }
+                | PulseCommand::ApplyPreset { .. }
+        )
+    }
+}
+
+async fn run_command(
+    module: &PulseModule,
+    client: &AsyncClient,
+    command: PulseCommand,
+) -> Result<(), pulsectl::Error> {
+    let (config, pulse) = (&module.config, module.pulse.as_ref());
+    if command.changes_volume() {
+        // the fade would overwrite the new volume
+        module.fade.abort();
+    }
+    match command {
+        PulseCommand::SetDefaultSink { sink_name } => {
+            let sink = find_sink(config, pulse, &sink_name).await?;
+            pulse.set_default_sink(&sink).await
+        }
+        PulseCommand::VolumeUp { step } => volume_up(config, pulse, step).await,
+        PulseCommand::VolumeDown { step } => pulse.volume_down(step).await,
+        PulseCommand::SetVolume { percent } => {
+            let sink = pulse.get_sink(DEFAULT_SINK).await?;
+            let percent = percent.min(volume_limit(config, &sink.name));
+            if config.pulseaudio.volume_ramp_ms == 0 {
+                return fade_volume(config, pulse, &sink, percent).await;
+            }
+            let (config, pulse, client) = (config.clone(), module.pulse.clone(), client.clone());
+            module.fade.start(async move {
+                if let Err(e) = fade_volume(&config, &pulse, &sink, percent).await {
+                    let command =
+                        serde_json::to_string(&PulseCommand::SetVolume { percent }).unwrap();
+                    publish_error(&client, &config, &command, e.to_string()).await;
+                }
+            });
+            Ok(())
+        }
+        PulseCommand::SetMute { mute } => pulse.set_mute(mute).await,
+        PulseCommand::SetSinkVolume { sink_name, volume } => {
+            let sink = pulse
+                .get_sink(sink_name.as_deref().unwrap_or(DEFAULT_SINK))
+                .await?;
+            let volume = limit_volume(config, &sink.name, volume);
+            pulse.set_sink_volume(&sink.name, volume).await
+        }
+        PulseCommand::SetChannelVolume {
+            sink_name,
+            channel,
+            volume,
+        } => {
+            let sink = pulse
+                .get_sink(sink_name.as_deref().unwrap_or(DEFAULT_SINK))
+                .await?;
+            let volume = limit_volume(config, &sink.name, volume);
+            pulse
+                .set_sink_channel_volume(&sink.name, &channel, volume)
+                .await
+        }
+        PulseCommand::SetBalance { sink_name, balance } => {
+            let sink_name = sink_name.as_deref().unwrap_or(DEFAULT_SINK);
+            pulse.set_balance(sink_name, balance).await
+        }
+        PulseCommand::ToggleMute => pulse.toggle_mute().await,
+        PulseCommand::CycleSinks => {
+            pulse
+                .cycle_sinks_where(|s| config.pulseaudio.sink_allowed(&s.name))
+                .await
+        }
+        PulseCommand::SetDefaultSource { source_name } => {
+            let Some(source) = pulse.find_source_by_name(&source_name).await else {
+                return Err(pulsectl::Error::PulseError(format!(
+                    "source {source_name} does not exist"
+                )));
             };
-            let Ok(current_sink) = pulse.get_default_sink().await else {
-                log::error!("Failed to get default sink");
-                continue;
+            pulse.set_default_source(&source).await
+        }
+        PulseCommand::SetSourceMute { mute } => pulse.set_source_mute(DEFAULT_SOURCE, mute).await,
+        PulseCommand::SetSinkInputVolume { index, percent } => {
+            // streams are limited like the sink they play on
+            let input = pulse
+                .list_sink_inputs()
+                .await?
+                .into_iter()
+                .find(|i| i.index == index)
+                .ok_or_else(|| {
+                    pulsectl::Error::PulseError(format!("sink input {index} does not exist"))
+                })?;
+            let limit = match pulse
+                .list_sinks()
+                .await?
+                .into_iter()
+                .find(|s| s.index == input.sink)
+            {
+                Some(sink) => volume_limit(config, &sink.name),
+                None => config.pulseaudio.max_volume,
             };
-            let Ok(current_volume) = pulse.get_default_volume().await else {
-                log::error!("Failed to get default volume");
-                continue;
+            pulse.set_sink_input_volume(index, percent.min(limit)).await
+        }
+        PulseCommand::SetSinkInputMute { index, mute } => {
+            pulse.set_sink_input_mute(index, mute).await
+        }
+        PulseCommand::MoveSinkInput { index, sink_name } => {
+            let sink = find_sink(config, pulse, &sink_name).await?;
+            pulse.move_sink_input(index, &sink).await
+        }
+        PulseCommand::ApplyPreset { preset_name } => {
+            let Some(preset) = config
+                .pulseaudio
+                .presets
+                .iter()
+                .find(|p| p.name == preset_name)
+            else {
+                return Err(pulsectl::Error::PulseError(format!(
+                    "preset {preset_name} does not exist"
+                )));
             };
-            let state = PulseState {
-                current_sink: current_sink.name,
-                current_volume: current_volume.value_percent,
-                sinks,
+            apply_preset(config, pulse, preset).await
+        }
+        PulseCommand::SetCardProfile { card_name, profile } => {
+            let Some(card) = pulse.find_card_by_name(&card_name).await else {
+                return Err(pulsectl::Error::PulseError(format!(
+                    "card {card_name} does not exist"
+                )));
             };
-            log::debug!(
-                "Publishing new state {:?} to {}",
-                &state,
-                &config.pulseaudio.state_topic
-            );
-            client
-                .publish(
-                    &config.pulseaudio.state_topic,
-                    QoS::AtLeastOnce,
-                    false,
-                    serde_json::to_string(&state).unwrap(),
-                )
-                .await
-                .unwrap();
-            log::debug!("Published new state");
+            pulse.set_card_profile(&card, &profile).await
         }
     }
-    Ok(())
 }
-pub async fn pulse_run() -> anyhow::Result<()> {
-    log::info!("Starting pulseaudio main task");
-    let config = Config::new();
-    let pulse = Pulseaudio::new(CLIENT_NAME_CMD);
 
-    let (client, mut eventloop) = config.get_client(&config.pulseaudio);
-    let (config_state, client_state) = (config.clone(), client.clone());
-    // autodiscover(&mut connection, &config, &client).await?;
+async fn publish_error(client: &AsyncClient, config: &Config, command: &str, error: String) {
+    log::error!("Error running pulseaudio command {}: {}", command, &error);
+    let payload = serde_json::to_string(&PulseError { command, error }).unwrap();
+    if let Err(e) = client
+        .publish(
+            &config.pulseaudio.error_topic,
+            QoS::AtLeastOnce,
+            false,
+            payload,
+        )
+        .await
+    {
+        log::error!("Could not publish pulseaudio error: {:?}", e);
+    }
+}
+
+pub struct PulseModule {
+    config: Config,
+    /// shared with the fade task
+    pulse: Arc<Pulseaudio>,
+    fade: Fade,
+}
 
-    // // then start the task to continuously update and publish the state in the background
-    task::spawn(async move {
-        pulse_state(client_state, &config_state).await.unwrap();
-    });
-    log::info!("Starting pulseaudio command loop");
-    while let Ok(event) = eventloop.poll().await {
-        if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(packet)) = event {
-            assert_eq!(packet.topic, config.pulseaudio.command_topic);
+impl PulseModule {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            pulse: Arc::new(Pulseaudio::new(CLIENT_NAME_CMD)),
+            fade: Fade::default(),
         }
     }
+}
 
-    Ok(())
+#[async_trait]
+impl Module for PulseModule {
+    fn name(&self) -> &str {
+        "pulseaudio"
+    }
+
+    fn availability(&self) -> &Availability {
+        &self.config.pulseaudio.availability
+    }
+
+    fn command_topics(&self) -> Vec<String> {
+        vec![self.config.pulseaudio.command_topic.clone()]
+    }
+
+    async fn discovery(&self, _client: &AsyncClient) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn state(
+        &self,
+        client: AsyncClient,
+        connects: watch::Receiver<u32>,
+        ready: Ready,
+    ) -> anyhow::Result<()> {
+        pulse_state(client, &self.config, connects, ready).await
+    }
+
+    async fn command(
+        &self,
+        client: &AsyncClient,
+        _topic: &str,
+        payload: &str,
+    ) -> anyhow::Result<()> {
+        // errors go to the error topic so automations can react to them
+        let pulse_command: PulseCommand = match serde_json::from_str(payload) {
+            Ok(command) => command,
+            Err(e) => {
+                publish_error(client, &self.config, payload, e.to_string()).await;
+                return Ok(());
+            }
+        };
+        log::debug!("Running pulseaudio command: {:?}", &pulse_command);
+        if let Err(e) = run_command(self, client, pulse_command).await {
+            publish_error(client, &self.config, payload, e.to_string()).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn percent(percent: u32) -> u32 {
+        VolumeLevel::Percent(f64::from(percent)).to_raw()
+    }
+
+    #[test]
+    fn volume_up_stops_at_the_limits() {
+        let config = Config::from_user_str(
+            "pulseaudio:\n  max_volume: 90\n  sink_max_volume:\n    - sink: \"bluez_output.*\"\n      max_volume: 50\n  quiet_hours:\n    start: \"22:00\"\n    end: \"07:00\"\n    max_volume: 30\n",
+        )
+        .unwrap();
+        let day = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let night = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+        let speakers = "alsa_output.analog-stereo";
+        let headset = "bluez_output.AA_BB.1";
+
+        let raised = raised_volumes(&config, speakers, &[percent(40)], 5, day);
+        assert_eq!(raised, Some(vec![percent(45)]));
+        // max_volume
+        let raised = raised_volumes(&config, speakers, &[percent(88)], 5, day);
+        assert_eq!(raised, Some(vec![percent(90)]));
+        assert_eq!(
+            raised_volumes(&config, speakers, &[percent(90)], 5, day),
+            None
+        );
+        // sink_max_volume replaces max_volume
+        let raised = raised_volumes(&config, headset, &[percent(48)], 5, day);
+        assert_eq!(raised, Some(vec![percent(50)]));
+        // quiet hours, a louder sink isn't raised any further
+        let raised = raised_volumes(&config, speakers, &[percent(28)], 5, night);
+        assert_eq!(raised, Some(vec![percent(30)]));
+        assert_eq!(
+            raised_volumes(&config, speakers, &[percent(60)], 5, night),
+            None
+        );
+    }
+
+    // every snapshot builds its maps from scratch, like get_state does
+    fn snapshot() -> PulseState {
+        let volume = serde_json::to_value(pulsectl::Volume::from_raw(percent(50))).unwrap();
+        let sink: SinkInfo = serde_json::from_value(serde_json::json!({
+            "index": 0,
+            "state": "RUNNING",
+            "name": "alsa_output.analog-surround-40",
+            "description": "Speakers",
+            "mute": false,
+            "channel_map": "front-left,front-right,rear-left,rear-right",
+            "volume": {
+                "front-left": volume,
+                "front-right": volume,
+                "rear-left": volume,
+                "rear-right": volume,
+            },
+            "properties": {
+                "alsa.card": "0",
+                "device.bus": "pci",
+                "device.class": "sound",
+                "device.icon_name": "audio-card-analog",
+                "device.profile.name": "analog-surround-40",
+            },
+        }))
+        .unwrap();
+        PulseState {
+            current_sink: sink.name.clone(),
+            current_sink_display_name: sink.description.clone(),
+            sink_display_names: BTreeMap::new(),
+            current_volume: sink.max_volume().value_percent,
+            current_mute: sink.mute,
+            sinks: vec![sink],
+            sources: Vec::new(),
+            current_source: None,
+            current_source_mute: None,
+            streams: Vec::new(),
+            cards: BTreeMap::new(),
+            sinks_playing: BTreeMap::new(),
+            playing: true,
+            recording_applications: Vec::new(),
+            recording: false,
+            current_preset: None,
+        }
+    }
+
+    #[test]
+    fn equal_snapshots_are_published_once() {
+        // the state task compares the payloads
+        let first = serde_json::to_string(&snapshot()).unwrap();
+        for _ in 0..10 {
+            assert_eq!(serde_json::to_string(&snapshot()).unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn switching_profiles_keeps_the_entities() {
+        let card = |active_profile: &str| CardState {
+            name: "bluez_card.AA_BB".to_owned(),
+            description: Some("Headset".to_owned()),
+            active_profile: Some(active_profile.to_owned()),
+            profiles: vec!["a2dp-sink".to_owned(), "headset-head-unit".to_owned()],
+        };
+        let mut a2dp = snapshot();
+        a2dp.cards
+            .insert("bluez_card_aa_bb".to_owned(), card("a2dp-sink"));
+        let mut headset = snapshot();
+        headset
+            .cards
+            .insert("bluez_card_aa_bb".to_owned(), card("headset-head-unit"));
+        assert_eq!(Entities::new(&a2dp), Entities::new(&headset));
+    }
+
+    #[test]
+    fn presets_match_the_state() {
+        let config = Config::from_user_str(
+            "pulseaudio:\n  max_volume: 90\n  sink_max_volume:\n    - sink: \"alsa_output.*\"\n      max_volume: 40\n  presets:\n    - name: \"loud\"\n      sink: \"alsa_output.*\"\n      volume: 80\n    - name: \"muted\"\n      sink: \"*\"\n      mute: true\n    - name: \"headset\"\n      sink: \"bluez_output.*\"\n",
+        )
+        .unwrap();
+        let with_volume = |volume: u32| {
+            let mut state = snapshot();
+            state.current_volume = pulsectl::Volume::from_raw(percent(volume)).value_percent;
+            state
+        };
+        let preset = |state: &PulseState| matching_preset(&config, state).map(|p| p.name.clone());
+
+        // the preset volume is above the sink's limit, applying it sets the limit
+        assert_eq!(preset(&with_volume(40)).as_deref(), Some("loud"));
+        assert_eq!(preset(&with_volume(50)), None);
+        // unset fields match anything
+        let mut muted = with_volume(50);
+        muted.current_mute = true;
+        assert_eq!(preset(&muted).as_deref(), Some("muted"));
+        let mut headset = with_volume(50);
+        headset.current_sink = "bluez_output.AA_BB.1".to_owned();
+        assert_eq!(preset(&headset).as_deref(), Some("headset"));
+    }
+
+    #[test]
+    fn volume_up_keeps_the_balance() {
+        let config = Config::new();
+        let day = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let raised = raised_volumes(&config, "sink", &[percent(98), percent(49)], 10, day);
+        assert_eq!(raised, Some(vec![percent(100), percent(50)]));
+        assert_eq!(scale_volumes(&[0, 0], percent(20)), vec![percent(20); 2]);
+    }
 }
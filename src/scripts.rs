@@ -0,0 +1,353 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use rumqttc::{self, AsyncClient, QoS};
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::task;
+
+use crate::config::{Config, ScriptConfig};
+use crate::homeassistant::{entity_key, Availability};
+use crate::module::{Module, Ready};
+
+/// result of the last command run by a script, published as entity attributes
+#[derive(serde::Serialize, Debug, Default)]
+struct ScriptResult {
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+    timed_out: bool,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct ScriptState {
+    /// only set for switches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(flatten)]
+    result: ScriptResult,
+}
+
+fn command_topic(config: &Config, script: &ScriptConfig) -> String {
+    format!(
+        "{}/{}/command",
+        &config.script_runner.topic_prefix,
+        entity_key(&script.name)
+    )
+}
+fn state_topic(config: &Config, script: &ScriptConfig) -> String {
+    format!(
+        "{}/{}/state",
+        &config.script_runner.topic_prefix,
+        entity_key(&script.name)
+    )
+}
+fn is_switch(script: &ScriptConfig) -> bool {
+    script.on_command.is_some() || script.off_command.is_some()
+}
+
+async fn run_script(script: &ScriptConfig, command: &str) -> ScriptResult {
+    log::debug!("Running script {}: {}", &script.name, command);
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .envs(&script.env)
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    if let Some(dir) = &script.working_directory {
+        cmd.current_dir(dir);
+    }
+    match tokio::time::timeout(Duration::from_secs(script.timeout), cmd.output()).await {
+        Ok(Ok(output)) => ScriptResult {
+            exit_code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            timed_out: false,
+        },
+        Ok(Err(e)) => {
+            log::error!("Could not run script {}: {:?}", &script.name, e);
+            ScriptResult {
+                stderr: e.to_string(),
+                ..Default::default()
+            }
+        }
+        Err(_) => {
+            log::error!("Script {} timed out", &script.name);
+            ScriptResult {
+                timed_out: true,
+                ..Default::default()
+            }
+        }
+    }
+}
+
+async fn publish_state(
+    client: &AsyncClient,
+    config: &Config,
+    script: &ScriptConfig,
+    state: ScriptState,
+) {
+    let payload = serde_json::to_string(&state).unwrap();
+    log::debug!("Publishing script state {}", &payload);
+    if let Err(e) = client
+        .publish(
+            state_topic(config, script),
+            QoS::AtLeastOnce,
+            false,
+            payload,
+        )
+        .await
+    {
+        log::error!(
+            "Could not publish state of script {}: {:?}",
+            &script.name,
+            e
+        );
+    }
+}
+
+/// Runs the state command of a switch and maps its exit code to the switch state.
+async fn switch_state(config: &Config, script: &ScriptConfig) -> Option<ScriptState> {
+    let state_command = script.state_command.as_ref()?;
+    let result = run_script(script, state_command).await;
+    let state = if result.exit_code == Some(0) {
+        config.switch_on_value.clone()
+    } else {
+        config.switch_off_value.clone()
+    };
+    Some(ScriptState {
+        state: Some(state),
+        result,
+    })
+}
+
+async fn handle_command(
+    client: AsyncClient,
+    config: Config,
+    script: ScriptConfig,
+    payload: String,
+) {
+    if !is_switch(&script) {
+        let Some(command) = &script.command else {
+            return;
+        };
+        let result = run_script(&script, command).await;
+        publish_state(
+            &client,
+            &config,
+            &script,
+            ScriptState {
+                state: None,
+                result,
+            },
+        )
+        .await;
+        return;
+    }
+    let (command, new_state) = if payload == config.switch_on_value {
+        (&script.on_command, config.switch_on_value.clone())
+    } else if payload == config.switch_off_value {
+        (&script.off_command, config.switch_off_value.clone())
+    } else {
+        log::error!(
+            "Invalid payload for script switch {}: {}",
+            &script.name,
+            &payload
+        );
+        return;
+    };
+    let Some(command) = command else {
+        log::error!("Script {} has no command for {}", &script.name, &payload);
+        return;
+    };
+    let result = run_script(&script, command).await;
+    // prefer the real state if it can be queried, otherwise assume the command worked
+    let state = match switch_state(&config, &script).await {
+        Some(queried) => queried.state,
+        None if result.exit_code == Some(0) => Some(new_state),
+        None => None,
+    };
+    publish_state(&client, &config, &script, ScriptState { state, result }).await;
+}
+
+async fn autodiscover(config: &Config, client: &AsyncClient, script: &ScriptConfig) {
+    let name = format!("{}{}", &config.script_runner.name_prefix, &script.name);
+    let unique_id = entity_key(&name);
+    let attributes_template =
+        "{{ {'exit_code': value_json.exit_code, 'stdout': value_json.stdout, 'stderr': value_json.stderr, 'timed_out': value_json.timed_out} | tojson }}"
+            .to_owned();
+    if is_switch(script) {
+        let switch = config.build_switch(
+            command_topic(config, script),
+            state_topic(config, script),
+            config.script_runner.availability.clone(),
+            name,
+            unique_id,
+            // there is no state without a state command, or when it failed
+            format!(
+                "{{{{ value_json.state | default('{off}') }}}}",
+                off = &config.switch_off_value,
+            ),
+            attributes_template,
+            config.switch_on_value.clone(),
+            config.switch_off_value.clone(),
+            state_topic(config, script),
+        );
+        config.publish_autodiscover(client, &switch).await;
+    } else {
+        let button = config.build_button(
+            command_topic(config, script),
+            config.script_runner.availability.clone(),
+            name,
+            unique_id,
+            state_topic(config, script),
+            attributes_template,
+        );
+        config.publish_autodiscover(client, &button).await;
+    }
+}
+
+// polls the state command of a switch
+async fn scripts_state_task(client: &AsyncClient, config: &Config, script: &ScriptConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(script.state_interval));
+    loop {
+        interval.tick().await;
+        if let Some(state) = switch_state(config, script).await {
+            publish_state(client, config, script, state).await;
+        }
+    }
+}
+
+pub struct ScriptsModule {
+    config: Config,
+    scripts: Vec<ScriptConfig>,
+}
+
+impl ScriptsModule {
+    /// Returns `None` if there are no valid scripts configured.
+    pub fn new(config: Config) -> Option<Self> {
+        let scripts: Vec<ScriptConfig> = config
+            .scripts
+            .iter()
+            .filter(|s| {
+                let valid = s.command.is_some() != is_switch(s);
+                if !valid {
+                    log::error!(
+                        "Script {} needs either `command` or `on_command`/`off_command`, ignoring it",
+                        &s.name
+                    );
+                }
+                valid
+            })
+            .cloned()
+            .collect();
+        if scripts.is_empty() {
+            log::info!("No scripts configured");
+            return None;
+        }
+        Some(Self { config, scripts })
+    }
+}
+
+#[async_trait]
+impl Module for ScriptsModule {
+    fn name(&self) -> &str {
+        "scripts"
+    }
+
+    fn availability(&self) -> &Availability {
+        &self.config.script_runner.availability
+    }
+
+    fn command_topics(&self) -> Vec<String> {
+        self.scripts
+            .iter()
+            .map(|s| command_topic(&self.config, s))
+            .collect()
+    }
+
+    async fn discovery(&self, client: &AsyncClient) -> anyhow::Result<()> {
+        for script in &self.scripts {
+            autodiscover(&self.config, client, script).await;
+        }
+        Ok(())
+    }
+
+    async fn state(
+        &self,
+        client: AsyncClient,
+        _connects: watch::Receiver<u32>,
+        mut ready: Ready,
+    ) -> anyhow::Result<()> {
+        // scripts have no backend that could be missing
+        ready.connected();
+        let tasks = self
+            .scripts
+            .iter()
+            .filter(|s| s.state_command.is_some())
+            .map(|s| scripts_state_task(&client, &self.config, s));
+        join_all(tasks).await;
+        // only reached if no script has a state command, which is not an error
+        std::future::pending().await
+    }
+
+    async fn command(
+        &self,
+        client: &AsyncClient,
+        topic: &str,
+        payload: &str,
+    ) -> anyhow::Result<()> {
+        let script = self
+            .scripts
+            .iter()
+            .find(|s| command_topic(&self.config, s) == topic)
+            .ok_or_else(|| anyhow::anyhow!("no script for topic {topic}"))?;
+        // scripts can take a while, don't block the other scripts
+        task::spawn(handle_command(
+            client.clone(),
+            self.config.clone(),
+            script.clone(),
+            payload.to_owned(),
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn script(command: &str, timeout: u64) -> ScriptConfig {
+        ScriptConfig {
+            name: "test".to_owned(),
+            command: Some(command.to_owned()),
+            on_command: None,
+            off_command: None,
+            state_command: None,
+            state_interval: 60,
+            timeout,
+            working_directory: Some("/".into()),
+            env: [("DESKTOP_TEST".to_owned(), "value".to_owned())].into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn run_script_output() {
+        let script = script("echo $DESKTOP_TEST; pwd; echo err >&2; exit 3", 5);
+        let result = run_script(&script, script.command.as_ref().unwrap()).await;
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.stdout, "value\n/\n");
+        assert_eq!(result.stderr, "err\n");
+        assert!(!result.timed_out);
+    }
+
+    #[tokio::test]
+    async fn run_script_timeout() {
+        let script = script("sleep 5", 1);
+        let result = run_script(&script, script.command.as_ref().unwrap()).await;
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, None);
+    }
+}
@@ -1,53 +1,196 @@
+use async_trait::async_trait;
 use swayipc_async::{Output, Workspace};
-use tokio::task;
+use tokio::sync::watch;
 
 use futures_util::stream::StreamExt;
 use rumqttc::{self, AsyncClient as MqttClient, QoS};
 use std::collections::HashMap;
 use swayipc_async::{Connection, EventType};
 
-use crate::{config::Config, homeassistant::Component};
+use crate::config::{Config, ProfileConfig};
+use crate::homeassistant::Availability;
+use crate::module::{Module, Ready};
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct SwayState {
     outputs: HashMap<String, Output>,
     workspaces: Vec<Workspace>,
     current_workspace: String,
+    /// profile that matches the connected outputs
+    current_profile: Option<String>,
 }
 
-async fn update_state(con: &mut Connection) -> SwayState {
-    let workspaces = con.get_workspaces().await.unwrap();
+/// Quotes an argument of a sway command, otherwise spaces split it and `;` or `,` start
+/// another command.
+fn quote(argument: &str) -> String {
+    format!(
+        "\"{}\"",
+        argument.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// what profiles identify an output by
+struct OutputId<'a> {
+    name: &'a str,
+    /// "<make> <model> <serial>" like in kanshi, stays the same on every connector
+    description: String,
+}
+
+impl<'a> From<&'a Output> for OutputId<'a> {
+    fn from(output: &'a Output) -> Self {
+        Self {
+            name: &output.name,
+            description: format!("{} {} {}", &output.make, &output.model, &output.serial),
+        }
+    }
+}
+
+fn output_matches(criteria: &str, output: &OutputId) -> bool {
+    criteria == output.name || criteria == output.description
+}
+
+/// A profile matches if every one of its outputs is connected and no other outputs are.
+fn matching_profile<'a>(
+    profiles: &'a [ProfileConfig],
+    outputs: &[OutputId],
+) -> Option<&'a ProfileConfig> {
+    profiles.iter().find(|profile| {
+        profile.outputs.len() == outputs.len()
+            && profile
+                .outputs
+                .iter()
+                .all(|p| outputs.iter().any(|o| output_matches(&p.name, o)))
+    })
+}
+
+/// All output settings of a profile as one batched command, so sway applies them together.
+fn profile_command(profile: &ProfileConfig, outputs: &[OutputId]) -> Option<String> {
+    let mut commands = Vec::new();
+    for settings in &profile.outputs {
+        let output = outputs.iter().find(|o| output_matches(&settings.name, o))?;
+        let name = output.name;
+        if !settings.enable {
+            commands.push(format!("output {name} disable"));
+            continue;
+        }
+        let mut cmd = format!("output {name} enable");
+        if let Some(mode) = &settings.mode {
+            cmd += &format!(" mode {mode}");
+        }
+        if let Some([x, y]) = settings.position {
+            cmd += &format!(" position {x} {y}");
+        }
+        if let Some(scale) = settings.scale {
+            cmd += &format!(" scale {scale}");
+        }
+        if let Some(transform) = &settings.transform {
+            cmd += &format!(" transform {transform}");
+        }
+        commands.push(cmd);
+    }
+    Some(commands.join("; "))
+}
+
+async fn apply_profile(con: &mut Connection, profile: &ProfileConfig) -> anyhow::Result<()> {
+    let outputs = con.get_outputs().await?;
+    let outputs: Vec<OutputId> = outputs.iter().map(OutputId::from).collect();
+    let cmd = profile_command(profile, &outputs)
+        .ok_or_else(|| anyhow::anyhow!("outputs of profile {} are not connected", &profile.name))?;
+    log::info!("Applying profile {}: {}", &profile.name, &cmd);
+    for result in con.run_command(&cmd).await? {
+        result?;
+    }
+    Ok(())
+}
+
+async fn update_state(con: &mut Connection, config: &Config) -> anyhow::Result<SwayState> {
+    let workspaces = con.get_workspaces().await?;
     let focused_workspace = workspaces.iter().filter(|w| w.focused).last();
     let current = match focused_workspace {
         Some(w) => w.name.clone(),
         None => "".to_owned(),
     };
-    let outputs = con.get_outputs().await.unwrap();
+    let outputs = con.get_outputs().await?;
+    let ids: Vec<OutputId> = outputs.iter().map(OutputId::from).collect();
+    let current_profile = matching_profile(&config.sway.profiles, &ids).map(|p| p.name.clone());
     let map: HashMap<String, Output> = outputs
         .iter()
         .map(|o| (o.name.clone(), o.clone()))
         .collect();
-    SwayState {
+    Ok(SwayState {
         outputs: map,
         current_workspace: current,
-        workspaces: con.get_workspaces().await.unwrap(),
-    }
+        workspaces,
+        current_profile,
+    })
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 #[serde(tag = "type")]
 enum SwayCommand {
-    OutputPowerOn { output_name: String },
-    OutputPowerOff { output_name: String },
-    OutputEnable { output_name: String },
-    OutputDisable { output_name: String },
+    OutputPowerOn {
+        output_name: String,
+    },
+    OutputPowerOff {
+        output_name: String,
+    },
+    OutputEnable {
+        output_name: String,
+    },
+    OutputDisable {
+        output_name: String,
+    },
+    /// refresh in mHz like in the output state, sway picks the highest one if it's missing
+    OutputSetMode {
+        output_name: String,
+        width: i32,
+        height: i32,
+        refresh: Option<i32>,
+    },
+    OutputSetScale {
+        output_name: String,
+        scale: f64,
+    },
+    /// normal, 90, 180, 270, flipped, flipped-90, flipped-180 or flipped-270
+    OutputSetTransform {
+        output_name: String,
+        transform: String,
+    },
+    OutputSetPosition {
+        output_name: String,
+        x: i32,
+        y: i32,
+    },
+    FocusWorkspace {
+        workspace_name: String,
+    },
+    ApplyProfile {
+        profile_name: String,
+    },
+}
+
+/// the transforms sway accepts in `output <name> transform <transform>`
+const TRANSFORMS: [&str; 8] = [
+    "normal",
+    "90",
+    "180",
+    "270",
+    "flipped",
+    "flipped-90",
+    "flipped-180",
+    "flipped-270",
+];
+
+/// formats a mode the way sway expects it in `output <name> mode <mode>`
+fn mode_to_string(width: i32, height: i32, refresh: i32) -> String {
+    format!("{width}x{height}@{:.3}Hz", refresh as f64 / 1000.0)
 }
 async fn autodiscover(
     con: &mut Connection,
     config: &Config,
     client: &MqttClient,
 ) -> anyhow::Result<()> {
-    for output in con.get_outputs().await.unwrap() {
+    for output in con.get_outputs().await? {
         {
             // dpms/power
             let cmd_on = SwayCommand::OutputPowerOn {
@@ -84,32 +227,97 @@ async fn autodiscover(
             );
             config.publish_autodiscover(client, &switch).await;
         }
+        {
+            // modes
+            let mut modes: Vec<String> = Vec::new();
+            for mode in &output.modes {
+                let mode = mode_to_string(mode.width, mode.height, mode.refresh);
+                if !modes.contains(&mode) {
+                    modes.push(mode);
+                }
+            }
+            if modes.is_empty() {
+                continue;
+            }
+            let name = format!(
+                "{prefix}{name}_mode",
+                prefix = &config.sway.name_prefix,
+                name = &output.name
+            );
+            let select = config.build_select(
+                modes,
+                config.sway.command_topic.clone(),
+                config.sway.state_topic.clone(),
+                config.sway.availability.clone(),
+                name.clone(),
+                name,
+                format!(
+                    "{{% set m = value_json.outputs['{key}'].current_mode %}}\
+                     {{{{ '%dx%d@%.3fHz' | format(m.width, m.height, m.refresh / 1000) if m else '' }}}}",
+                    key = &output.name,
+                ),
+                Some(format!(
+                    "{{% set res, rate = value.split('@') %}}{{% set w, h = res.split('x') %}}\
+                     {{\"type\": \"OutputSetMode\", \"output_name\": \"{key}\", \
+                     \"width\": {{{{ w }}}}, \"height\": {{{{ h }}}}, \
+                     \"refresh\": {{{{ (rate[:-2] | float * 1000) | round | int }}}}}}",
+                    key = &output.name,
+                )),
+                format!(
+                    "{{{{ value_json.outputs['{key}'] | tojson }}}}",
+                    key = &output.name
+                ),
+            );
+            config.publish_autodiscover(client, &select).await;
+        }
     }
-    {
-        // let workspaces: Vec<String> = connection
-        //     .get_workspaces()
-        //     .await
-        //     .unwrap()
-        //     .iter()
-        //     .map(|w| w.name.clone())
-        //     .collect();
-        // config.sway.workspaces_select.options = workspaces;
-        // let topic = config.get_autodiscover_topic(&config.sway.workspaces_select);
-        // client
-        //     .publish(
-        //         topic,
-        //         QoS::AtLeastOnce,
-        //         false,
-        //         serde_json::to_string(&config.sway.workspaces_select).unwrap(),
-        //     )
-        //     .await
-        //     .unwrap();
+    if !config.sway.profiles.is_empty() {
+        let name = format!("{prefix}profile", prefix = &config.sway.name_prefix);
+        let select = config.build_select(
+            config
+                .sway
+                .profiles
+                .iter()
+                .map(|p| p.name.clone())
+                .collect(),
+            config.sway.command_topic.clone(),
+            config.sway.state_topic.clone(),
+            config.sway.availability.clone(),
+            name.clone(),
+            name,
+            "{{ value_json.current_profile or '' }}".to_owned(),
+            Some(r#"{"type": "ApplyProfile", "profile_name": "{{ value }}"}"#.to_owned()),
+            "{{ {'current_profile': value_json.current_profile} | tojson }}".to_owned(),
+        );
+        config.publish_autodiscover(client, &select).await;
     }
     Ok(())
 }
 
+async fn autodiscover_workspaces(config: &Config, client: &MqttClient, workspaces: &[String]) {
+    let name = format!("{prefix}workspaces", prefix = &config.sway.name_prefix);
+    let select = config.build_select(
+        workspaces.to_vec(),
+        config.sway.command_topic.clone(),
+        config.sway.state_topic.clone(),
+        config.sway.availability.clone(),
+        name.clone(),
+        name,
+        "{{ value_json.current_workspace }}".to_owned(),
+        Some(r#"{"type": "FocusWorkspace", "workspace_name": "{{ value }}"}"#.to_owned()),
+        "{{ {'workspaces': value_json.workspaces | map(attribute='name') | list} | tojson }}"
+            .to_owned(),
+    );
+    config.publish_autodiscover(client, &select).await;
+}
+
 // outputs the current state of sway to the topic
-pub async fn sway_state_task(client: MqttClient, config: Config) -> anyhow::Result<()> {
+async fn sway_state_task(
+    client: MqttClient,
+    config: &Config,
+    mut connects: watch::Receiver<u32>,
+    mut ready: Ready,
+) -> anyhow::Result<()> {
     log::info!("Starting sway state task");
     let subs = [
         EventType::Workspace,
@@ -126,9 +334,51 @@ pub async fn sway_state_task(client: MqttClient, config: Config) -> anyhow::Resu
     let mut connection = Connection::new().await?;
 
     let mut events = Connection::new().await?.subscribe(subs).await?;
+    ready.connected();
+    // workspace names from the last published discovery, the select options need to be
+    // republished whenever workspaces are created or destroyed
+    let mut discovered_workspaces: Vec<String> = Vec::new();
+    // profile that matched the outputs last time, so it's only applied once per change
+    let mut last_profile: Option<String> = None;
+    // outputs of the last state, the discovery of the module only knows the outputs that were
+    // connected when it ran
+    let mut known_outputs: Option<Vec<String>> = None;
     log::info!("Starting sway state loop");
-    while let Some(_event) = events.next().await {
-        let state = update_state(&mut connection).await;
+    // publish the initial state right away, afterwards on every event
+    loop {
+        let state = update_state(&mut connection, config).await?;
+        if state.current_profile != last_profile {
+            last_profile = state.current_profile.clone();
+            let profile = config
+                .sway
+                .profiles
+                .iter()
+                .find(|p| Some(&p.name) == last_profile.as_ref());
+            if let (true, Some(profile)) = (config.sway.auto_apply_profile, profile) {
+                if let Err(e) = apply_profile(&mut connection, profile).await {
+                    log::error!("Could not apply profile {}: {:?}", &profile.name, e);
+                }
+            }
+        }
+        let mut outputs: Vec<String> = state.outputs.keys().cloned().collect();
+        outputs.sort();
+        let new_output = known_outputs
+            .as_ref()
+            .is_some_and(|known| outputs.iter().any(|o| !known.contains(o)));
+        if new_output {
+            log::debug!("Outputs changed, publishing autodiscover: {:?}", &outputs);
+            autodiscover(&mut connection, config, &client).await?;
+        }
+        known_outputs = Some(outputs);
+        let workspaces: Vec<String> = state.workspaces.iter().map(|w| w.name.clone()).collect();
+        if workspaces != discovered_workspaces {
+            log::debug!(
+                "Workspaces changed, publishing autodiscover: {:?}",
+                &workspaces
+            );
+            autodiscover_workspaces(config, &client, &workspaces).await;
+            discovered_workspaces = workspaces;
+        }
         client
             .publish(
                 &config.sway.state_topic,
@@ -136,79 +386,210 @@ pub async fn sway_state_task(client: MqttClient, config: Config) -> anyhow::Resu
                 false,
                 serde_json::to_string(&state)?,
             )
-            .await
-            .unwrap();
+            .await?;
+        tokio::select! {
+            event = events.next() => {
+                if event.is_none() {
+                    break;
+                }
+            }
+            Ok(()) = connects.changed() => {
+                // the broker might have lost the discovery configs
+                discovered_workspaces.clear();
+            }
+        }
     }
     Ok(())
 }
 
-pub async fn sway_run() -> anyhow::Result<()> {
-    log::info!("Starting sway main task");
-    let config = Config::new();
-
-    let (client, mut eventloop) = config.get_client(&config.sway);
-    let (config_state, client_state) = (config.clone(), client.clone());
-    // auto discover first to add the entities to home-assistant
-    let mut connection = Connection::new().await?;
-    autodiscover(&mut connection, &config, &client).await?;
-
-    // then start the task to continuously update and publish the state in the background
-    let _handle = task::spawn(async move {
-        let result = sway_state_task(client_state, config_state).await;
-        log::error!("Sway state task exited with error: {:?}", &result);
-    });
-
-    // then output the online message
-    client
-        .publish(
-            &config.sway.availability.topic,
-            QoS::AtLeastOnce,
-            config.mqtt.retain_last_will,
-            config.sway.availability.payload_available.clone(),
-        )
-        .await?;
-
-    client
-        .subscribe(&config.sway.command_topic, QoS::AtLeastOnce)
-        .await?;
-
-    // loop
-    while let Ok(event) = eventloop.poll().await {
-        if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(message)) = event {
-            assert_eq!(message.topic, config.sway.command_topic);
-            let Ok(string) = std::str::from_utf8(&message.payload) else {
-                log::error!("Received invalid utf8 string from mqtt");
-                continue;
-            };
-            let Ok(sway_command) = serde_json::from_str(string) else {
-                log::error!("Could not parse json from mqtt {:?}", &string);
-                continue;
-            };
-            let cmd = match sway_command {
-                SwayCommand::OutputPowerOn { output_name } => {
-                    format!("output {output_name} power on")
-                }
-                SwayCommand::OutputPowerOff { output_name } => {
-                    format!("output {output_name} power off")
-                }
-                SwayCommand::OutputEnable { output_name } => {
-                    format!("output {output_name} enable")
-                }
-                SwayCommand::OutputDisable { output_name } => {
-                    format!("output {output_name} disable")
-                }
-            };
-            log::debug!("Running sway command: {}", &cmd);
-            let Ok(output) = connection.run_command(&cmd).await else {
-                log::error!("Could not run command: {}", &cmd);
-                continue;
-            };
-            for result in output {
-                if result.is_err() {
-                    log::error!("Error running command: {}", &cmd);
-                }
+async fn run_command(
+    connection: &mut Connection,
+    config: &Config,
+    sway_command: SwayCommand,
+) -> anyhow::Result<()> {
+    let cmd = match sway_command {
+        SwayCommand::OutputPowerOn { output_name } => {
+            format!("output {} power on", quote(&output_name))
+        }
+        SwayCommand::OutputPowerOff { output_name } => {
+            format!("output {} power off", quote(&output_name))
+        }
+        SwayCommand::OutputEnable { output_name } => {
+            format!("output {} enable", quote(&output_name))
+        }
+        SwayCommand::OutputDisable { output_name } => {
+            format!("output {} disable", quote(&output_name))
+        }
+        SwayCommand::OutputSetMode {
+            output_name,
+            width,
+            height,
+            refresh: Some(refresh),
+        } => {
+            let mode = mode_to_string(width, height, refresh);
+            format!("output {} mode {mode}", quote(&output_name))
+        }
+        SwayCommand::OutputSetMode {
+            output_name,
+            width,
+            height,
+            refresh: None,
+        } => {
+            format!("output {} mode {width}x{height}", quote(&output_name))
+        }
+        SwayCommand::OutputSetScale { output_name, scale } => {
+            format!("output {} scale {scale}", quote(&output_name))
+        }
+        SwayCommand::OutputSetTransform {
+            output_name,
+            transform,
+        } => {
+            // anything else would be pasted into the command as it is
+            if !TRANSFORMS.contains(&transform.as_str()) {
+                anyhow::bail!("unknown transform {transform}");
             }
+            format!("output {} transform {transform}", quote(&output_name))
+        }
+        SwayCommand::OutputSetPosition { output_name, x, y } => {
+            format!("output {} position {x} {y}", quote(&output_name))
+        }
+        SwayCommand::FocusWorkspace { workspace_name } => {
+            format!("workspace {}", quote(&workspace_name))
         }
+        SwayCommand::ApplyProfile { profile_name } => {
+            let profile = config
+                .sway
+                .profiles
+                .iter()
+                .find(|p| p.name == profile_name)
+                .ok_or_else(|| anyhow::anyhow!("unknown profile {profile_name}"))?;
+            return apply_profile(connection, profile).await;
+        }
+    };
+    log::debug!("Running sway command: {}", &cmd);
+    for result in connection.run_command(&cmd).await? {
+        result?;
     }
     Ok(())
 }
+
+pub struct SwayModule {
+    config: Config,
+}
+
+impl SwayModule {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Module for SwayModule {
+    fn name(&self) -> &str {
+        "sway"
+    }
+
+    fn availability(&self) -> &Availability {
+        &self.config.sway.availability
+    }
+
+    fn command_topics(&self) -> Vec<String> {
+        vec![self.config.sway.command_topic.clone()]
+    }
+
+    async fn discovery(&self, client: &MqttClient) -> anyhow::Result<()> {
+        // the workspaces are discovered by the state task since they change all the time
+        let mut connection = Connection::new().await?;
+        autodiscover(&mut connection, &self.config, client).await
+    }
+
+    async fn state(
+        &self,
+        client: MqttClient,
+        connects: watch::Receiver<u32>,
+        ready: Ready,
+    ) -> anyhow::Result<()> {
+        sway_state_task(client, &self.config, connects, ready).await
+    }
+
+    async fn command(
+        &self,
+        _client: &MqttClient,
+        _topic: &str,
+        payload: &str,
+    ) -> anyhow::Result<()> {
+        let sway_command: SwayCommand = serde_json::from_str(payload)?;
+        let mut connection = Connection::new().await?;
+        run_command(&mut connection, &self.config, sway_command).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn outputs() -> Vec<OutputId<'static>> {
+        vec![
+            OutputId {
+                name: "eDP-1",
+                description: "BOE 0x0BCA Unknown".to_owned(),
+            },
+            OutputId {
+                name: "DP-3",
+                description: "Dell Inc. DELL U2720Q ABC123".to_owned(),
+            },
+        ]
+    }
+
+    fn profiles() -> Vec<ProfileConfig> {
+        Config::from_user_str(
+            "sway:\n  profiles:\n    - name: \"laptop\"\n      outputs:\n        - name: \"eDP-1\"\n    - name: \"docked\"\n      outputs:\n        - name: \"eDP-1\"\n          enable: false\n        - name: \"Dell Inc. DELL U2720Q ABC123\"\n          mode: \"3840x2160@60Hz\"\n          position: [0, 0]\n          scale: 1.5\n",
+        )
+        .unwrap()
+        .sway
+        .profiles
+    }
+
+    #[test]
+    fn profiles_match_by_name_or_description() {
+        let profiles = profiles();
+        let outputs = outputs();
+        // the monitor is matched by make, model and serial, whatever port it's on
+        let profile = matching_profile(&profiles, &outputs).unwrap();
+        assert_eq!(profile.name, "docked");
+        assert_eq!(
+            matching_profile(&profiles, &outputs[..1]).unwrap().name,
+            "laptop"
+        );
+    }
+
+    #[test]
+    fn profiles_need_exactly_their_outputs() {
+        let profiles = profiles();
+        let mut outputs = outputs();
+        outputs.push(OutputId {
+            name: "HDMI-A-1",
+            description: "Unknown TV 0".to_owned(),
+        });
+        assert!(matching_profile(&profiles, &outputs).is_none());
+        assert!(matching_profile(&profiles, &[]).is_none());
+    }
+
+    #[test]
+    fn profile_command_configures_every_output() {
+        let profiles = profiles();
+        assert_eq!(
+            profile_command(&profiles[1], &outputs()).unwrap(),
+            "output eDP-1 disable; output DP-3 enable mode 3840x2160@60Hz position 0 0 scale 1.5"
+        );
+        // the dell is not connected
+        assert!(profile_command(&profiles[1], &outputs()[..1]).is_none());
+    }
+
+    #[test]
+    fn workspace_names_are_quoted() {
+        assert_eq!(quote("1: web"), r#""1: web""#);
+        assert_eq!(quote(r#"a"; exit"#), r#""a\"; exit""#);
+        assert_eq!(quote(r"back\slash"), r#""back\\slash""#);
+    }
+}
@@ -0,0 +1,868 @@
+//! Output management for wlroots based compositors (river, labwc, Hyprland, ...) through the
+//! zwlr_output_management_v1 and zwlr_output_power_management_v1 protocols.
+
+use std::collections::HashMap;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+
+use async_trait::async_trait;
+use rumqttc::{self, AsyncClient as MqttClient, QoS};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch, Mutex};
+use wayland_client::backend::ObjectId;
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::protocol::{wl_output, wl_registry};
+use wayland_client::{event_created_child, Connection, Dispatch, Proxy, QueueHandle, WEnum};
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_configuration_head_v1::ZwlrOutputConfigurationHeadV1,
+    zwlr_output_configuration_v1::{self, ZwlrOutputConfigurationV1},
+    zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+    zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+    zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+};
+use wayland_protocols_wlr::output_power_management::v1::client::{
+    zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+    zwlr_output_power_v1::{self, ZwlrOutputPowerV1},
+};
+
+use crate::config::Config;
+use crate::homeassistant::Availability;
+use crate::module::{Module, Ready};
+
+/// Same format as the output commands of the sway module, so they can be used interchangeably.
+/// The variant names are the command types, so they keep the prefix.
+#[allow(clippy::enum_variant_names)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "type")]
+enum WlrCommand {
+    OutputPowerOn {
+        output_name: String,
+    },
+    OutputPowerOff {
+        output_name: String,
+    },
+    OutputEnable {
+        output_name: String,
+    },
+    OutputDisable {
+        output_name: String,
+    },
+    /// refresh in mHz, the highest available refresh rate is used if it's missing
+    OutputSetMode {
+        output_name: String,
+        width: i32,
+        height: i32,
+        refresh: Option<i32>,
+    },
+    OutputSetScale {
+        output_name: String,
+        scale: f64,
+    },
+    OutputSetTransform {
+        output_name: String,
+        transform: String,
+    },
+    OutputSetPosition {
+        output_name: String,
+        x: i32,
+        y: i32,
+    },
+}
+
+// the published state mirrors the fields of swayipc's `Output`
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+struct OutputMode {
+    width: i32,
+    height: i32,
+    refresh: i32,
+}
+#[derive(serde::Serialize, Debug)]
+struct Rect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+#[derive(serde::Serialize, Debug)]
+struct Output {
+    name: String,
+    make: String,
+    model: String,
+    serial: String,
+    active: bool,
+    dpms: bool,
+    power: bool,
+    modes: Vec<OutputMode>,
+    current_mode: Option<OutputMode>,
+    rect: Rect,
+    scale: f64,
+    transform: String,
+}
+#[derive(serde::Serialize, Debug)]
+struct WlrState {
+    outputs: HashMap<String, Output>,
+}
+
+#[derive(Default)]
+struct Mode {
+    width: i32,
+    height: i32,
+    refresh: i32,
+}
+
+struct Head {
+    proxy: ZwlrOutputHeadV1,
+    name: String,
+    make: String,
+    model: String,
+    serial: String,
+    enabled: bool,
+    modes: Vec<ZwlrOutputModeV1>,
+    current_mode: Option<ObjectId>,
+    x: i32,
+    y: i32,
+    transform: wl_output::Transform,
+    scale: f64,
+}
+
+/// settings of a head for a new configuration
+struct HeadSettings {
+    enabled: bool,
+    mode: Option<ZwlrOutputModeV1>,
+    custom_mode: Option<(i32, i32, i32)>,
+    x: i32,
+    y: i32,
+    transform: wl_output::Transform,
+    scale: f64,
+}
+
+struct Outputs {
+    manager: ZwlrOutputManagerV1,
+    power_manager: Option<ZwlrOutputPowerManagerV1>,
+    heads: HashMap<ObjectId, Head>,
+    modes: HashMap<ObjectId, Mode>,
+    /// wl_output registry name -> (output, output name once known)
+    wl_outputs: HashMap<u32, (wl_output::WlOutput, Option<String>)>,
+    /// output name -> power controller and whether the output is powered on
+    power: HashMap<String, (ZwlrOutputPowerV1, bool)>,
+    serial: u32,
+    /// set when the state needs to be published again
+    changed: bool,
+}
+
+fn transform_to_string(transform: wl_output::Transform) -> &'static str {
+    match transform {
+        wl_output::Transform::_90 => "90",
+        wl_output::Transform::_180 => "180",
+        wl_output::Transform::_270 => "270",
+        wl_output::Transform::Flipped => "flipped",
+        wl_output::Transform::Flipped90 => "flipped-90",
+        wl_output::Transform::Flipped180 => "flipped-180",
+        wl_output::Transform::Flipped270 => "flipped-270",
+        _ => "normal",
+    }
+}
+
+fn transform_from_string(transform: &str) -> Option<wl_output::Transform> {
+    match transform {
+        "normal" => Some(wl_output::Transform::Normal),
+        "90" => Some(wl_output::Transform::_90),
+        "180" => Some(wl_output::Transform::_180),
+        "270" => Some(wl_output::Transform::_270),
+        "flipped" => Some(wl_output::Transform::Flipped),
+        "flipped-90" => Some(wl_output::Transform::Flipped90),
+        "flipped-180" => Some(wl_output::Transform::Flipped180),
+        "flipped-270" => Some(wl_output::Transform::Flipped270),
+        _ => None,
+    }
+}
+
+impl Outputs {
+    fn output_mode(&self, mode: &ZwlrOutputModeV1) -> Option<OutputMode> {
+        self.modes.get(&mode.id()).map(|m| OutputMode {
+            width: m.width,
+            height: m.height,
+            refresh: m.refresh,
+        })
+    }
+
+    fn state(&self) -> WlrState {
+        let outputs = self
+            .heads
+            .values()
+            .map(|head| {
+                let current_mode = head
+                    .current_mode
+                    .as_ref()
+                    .and_then(|id| self.modes.get(id))
+                    .map(|m| OutputMode {
+                        width: m.width,
+                        height: m.height,
+                        refresh: m.refresh,
+                    });
+                let (width, height) = match &current_mode {
+                    Some(mode) if head.enabled => {
+                        let (w, h) = (
+                            (mode.width as f64 / head.scale) as i32,
+                            (mode.height as f64 / head.scale) as i32,
+                        );
+                        match head.transform {
+                            wl_output::Transform::_90
+                            | wl_output::Transform::_270
+                            | wl_output::Transform::Flipped90
+                            | wl_output::Transform::Flipped270 => (h, w),
+                            _ => (w, h),
+                        }
+                    }
+                    _ => (0, 0),
+                };
+                // outputs without power management are on as long as they are enabled
+                let power = self
+                    .power
+                    .get(&head.name)
+                    .map(|(_, on)| *on)
+                    .unwrap_or(head.enabled);
+                let output = Output {
+                    name: head.name.clone(),
+                    make: head.make.clone(),
+                    model: head.model.clone(),
+                    serial: head.serial.clone(),
+                    active: head.enabled,
+                    dpms: power,
+                    power,
+                    modes: head
+                        .modes
+                        .iter()
+                        .filter_map(|m| self.output_mode(m))
+                        .collect(),
+                    current_mode,
+                    rect: Rect {
+                        x: head.x,
+                        y: head.y,
+                        width,
+                        height,
+                    },
+                    scale: head.scale,
+                    transform: transform_to_string(head.transform).to_owned(),
+                };
+                (head.name.clone(), output)
+            })
+            .collect();
+        WlrState { outputs }
+    }
+
+    fn find_head(&self, name: &str) -> anyhow::Result<&Head> {
+        self.heads
+            .values()
+            .find(|h| h.name == name)
+            .ok_or_else(|| anyhow::anyhow!("unknown output {name}"))
+    }
+
+    fn set_power(&self, name: &str, on: bool) -> anyhow::Result<()> {
+        let (power, _) = self
+            .power
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("power management is not available for {name}"))?;
+        power.set_mode(if on {
+            zwlr_output_power_v1::Mode::On
+        } else {
+            zwlr_output_power_v1::Mode::Off
+        });
+        Ok(())
+    }
+
+    /// Applies a new configuration in which only the head `name` is changed by `change`.
+    /// The protocol requires all heads to be configured, the others keep their current settings.
+    fn configure(
+        &self,
+        qh: &QueueHandle<Self>,
+        name: &str,
+        change: impl FnOnce(&Head, &mut HeadSettings) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let target = self.find_head(name)?;
+        let mut settings: Vec<(&Head, HeadSettings)> = self
+            .heads
+            .values()
+            .map(|head| {
+                let settings = HeadSettings {
+                    enabled: head.enabled,
+                    mode: head
+                        .modes
+                        .iter()
+                        .find(|m| Some(m.id()) == head.current_mode)
+                        .cloned(),
+                    custom_mode: None,
+                    x: head.x,
+                    y: head.y,
+                    transform: head.transform,
+                    scale: head.scale,
+                };
+                (head, settings)
+            })
+            .collect();
+        if let Some((head, s)) = settings
+            .iter_mut()
+            .find(|(head, _)| head.proxy == target.proxy)
+        {
+            change(head, s)?;
+        }
+        let configuration = self.manager.create_configuration(self.serial, qh, ());
+        for (head, s) in settings {
+            if !s.enabled {
+                configuration.disable_head(&head.proxy);
+                continue;
+            }
+            let config_head = configuration.enable_head(&head.proxy, qh, ());
+            if let Some((width, height, refresh)) = s.custom_mode {
+                config_head.set_custom_mode(width, height, refresh);
+            } else if let Some(mode) = &s.mode {
+                config_head.set_mode(mode);
+            }
+            config_head.set_position(s.x, s.y);
+            config_head.set_transform(s.transform);
+            config_head.set_scale(s.scale);
+        }
+        configuration.apply();
+        Ok(())
+    }
+
+    fn run_command(&self, qh: &QueueHandle<Self>, command: WlrCommand) -> anyhow::Result<()> {
+        match command {
+            WlrCommand::OutputPowerOn { output_name } => self.set_power(&output_name, true),
+            WlrCommand::OutputPowerOff { output_name } => self.set_power(&output_name, false),
+            WlrCommand::OutputEnable { output_name } => {
+                self.configure(qh, &output_name, |head, s| {
+                    s.enabled = true;
+                    if s.mode.is_none() {
+                        // a head that was never enabled has no current mode
+                        s.mode = head.modes.first().cloned();
+                    }
+                    Ok(())
+                })
+            }
+            WlrCommand::OutputDisable { output_name } => {
+                self.configure(qh, &output_name, |_, s| {
+                    s.enabled = false;
+                    Ok(())
+                })
+            }
+            WlrCommand::OutputSetMode {
+                output_name,
+                width,
+                height,
+                refresh,
+            } => self.configure(qh, &output_name, |head, s| {
+                let mode = head
+                    .modes
+                    .iter()
+                    .filter_map(|m| self.modes.get(&m.id()).map(|mode| (m, mode)))
+                    .filter(|(_, mode)| mode.width == width && mode.height == height)
+                    .filter(|(_, mode)| refresh.is_none_or(|r| mode.refresh == r))
+                    .max_by_key(|(_, mode)| mode.refresh)
+                    .map(|(m, _)| m.clone());
+                match (mode, refresh) {
+                    (Some(mode), _) => s.mode = Some(mode),
+                    (None, Some(refresh)) => s.custom_mode = Some((width, height, refresh)),
+                    // 0 lets the compositor pick the refresh rate
+                    (None, None) => s.custom_mode = Some((width, height, 0)),
+                }
+                Ok(())
+            }),
+            WlrCommand::OutputSetScale { output_name, scale } => {
+                self.configure(qh, &output_name, |_, s| {
+                    s.scale = scale;
+                    Ok(())
+                })
+            }
+            WlrCommand::OutputSetTransform {
+                output_name,
+                transform,
+            } => {
+                let transform = transform_from_string(&transform)
+                    .ok_or_else(|| anyhow::anyhow!("invalid transform {transform}"))?;
+                self.configure(qh, &output_name, |_, s| {
+                    s.transform = transform;
+                    Ok(())
+                })
+            }
+            WlrCommand::OutputSetPosition { output_name, x, y } => {
+                self.configure(qh, &output_name, |_, s| {
+                    s.x = x;
+                    s.y = y;
+                    Ok(())
+                })
+            }
+        }
+    }
+
+    fn add_power(&mut self, qh: &QueueHandle<Self>, output: &wl_output::WlOutput, name: String) {
+        if let Some(power_manager) = &self.power_manager {
+            let power = power_manager.get_output_power(output, qh, name.clone());
+            self.power.insert(name, (power, true));
+        }
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for Outputs {
+    fn event(
+        state: &mut Self,
+        registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } if interface == wl_output::WlOutput::interface().name => {
+                let output = registry.bind(name, version.min(4), qh, name);
+                state.wl_outputs.insert(name, (output, None));
+            }
+            wl_registry::Event::GlobalRemove { name } => {
+                if let Some((output, output_name)) = state.wl_outputs.remove(&name) {
+                    if let Some((power, _)) = output_name.and_then(|n| state.power.remove(&n)) {
+                        power.destroy();
+                    }
+                    if output.version() >= 3 {
+                        output.release();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<wl_output::WlOutput, u32> for Outputs {
+    fn event(
+        state: &mut Self,
+        output: &wl_output::WlOutput,
+        event: wl_output::Event,
+        global_name: &u32,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        // wl_output only tells its name since version 4, older outputs can't get power management
+        if let wl_output::Event::Name { name } = event {
+            if let Some((_, output_name)) = state.wl_outputs.get_mut(global_name) {
+                *output_name = Some(name.clone());
+            }
+            state.add_power(qh, output, name);
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputManagerV1, ()> for Outputs {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrOutputManagerV1,
+        event: zwlr_output_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_manager_v1::Event::Head { head } => {
+                state.heads.insert(
+                    head.id(),
+                    Head {
+                        proxy: head,
+                        name: String::new(),
+                        make: String::new(),
+                        model: String::new(),
+                        serial: String::new(),
+                        enabled: false,
+                        modes: Vec::new(),
+                        current_mode: None,
+                        x: 0,
+                        y: 0,
+                        transform: wl_output::Transform::Normal,
+                        scale: 1.0,
+                    },
+                );
+            }
+            // all head properties are sent before `done`, so this is when the state is consistent
+            zwlr_output_manager_v1::Event::Done { serial } => {
+                state.serial = serial;
+                state.changed = true;
+            }
+            zwlr_output_manager_v1::Event::Finished => {
+                log::error!("Compositor stopped the output manager");
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(Outputs, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for Outputs {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputHeadV1,
+        event: zwlr_output_head_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_output_head_v1::Event::Finished = event {
+            if let Some(head) = state.heads.remove(&proxy.id()) {
+                for mode in head.modes {
+                    state.modes.remove(&mode.id());
+                }
+            }
+            if proxy.version() >= 3 {
+                proxy.release();
+            }
+            return;
+        }
+        let Some(head) = state.heads.get_mut(&proxy.id()) else {
+            return;
+        };
+        match event {
+            zwlr_output_head_v1::Event::Name { name } => head.name = name,
+            zwlr_output_head_v1::Event::Make { make } => head.make = make,
+            zwlr_output_head_v1::Event::Model { model } => head.model = model,
+            zwlr_output_head_v1::Event::SerialNumber { serial_number } => {
+                head.serial = serial_number
+            }
+            zwlr_output_head_v1::Event::Mode { mode } => {
+                state.modes.insert(mode.id(), Mode::default());
+                head.modes.push(mode);
+            }
+            zwlr_output_head_v1::Event::Enabled { enabled } => head.enabled = enabled != 0,
+            zwlr_output_head_v1::Event::CurrentMode { mode } => head.current_mode = Some(mode.id()),
+            zwlr_output_head_v1::Event::Position { x, y } => (head.x, head.y) = (x, y),
+            zwlr_output_head_v1::Event::Transform {
+                transform: WEnum::Value(transform),
+            } => head.transform = transform,
+            zwlr_output_head_v1::Event::Scale { scale } => head.scale = scale,
+            _ => {}
+        }
+    }
+
+    event_created_child!(Outputs, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputModeV1, ()> for Outputs {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputModeV1,
+        event: zwlr_output_mode_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_mode_v1::Event::Finished => {
+                state.modes.remove(&proxy.id());
+                for head in state.heads.values_mut() {
+                    head.modes.retain(|m| m != proxy);
+                }
+                if proxy.version() >= 3 {
+                    proxy.release();
+                }
+            }
+            zwlr_output_mode_v1::Event::Size { width, height } => {
+                if let Some(mode) = state.modes.get_mut(&proxy.id()) {
+                    (mode.width, mode.height) = (width, height);
+                }
+            }
+            zwlr_output_mode_v1::Event::Refresh { refresh } => {
+                if let Some(mode) = state.modes.get_mut(&proxy.id()) {
+                    mode.refresh = refresh;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputConfigurationV1, ()> for Outputs {
+    fn event(
+        _: &mut Self,
+        proxy: &ZwlrOutputConfigurationV1,
+        event: zwlr_output_configuration_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_configuration_v1::Event::Succeeded => {
+                log::debug!("Output configuration applied");
+            }
+            zwlr_output_configuration_v1::Event::Failed => {
+                log::error!("Compositor rejected the output configuration");
+            }
+            zwlr_output_configuration_v1::Event::Cancelled => {
+                log::error!("Output configuration was cancelled, outputs changed in the meantime");
+            }
+            _ => {}
+        }
+        proxy.destroy();
+    }
+}
+
+impl Dispatch<ZwlrOutputConfigurationHeadV1, ()> for Outputs {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrOutputConfigurationHeadV1,
+        _: <ZwlrOutputConfigurationHeadV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrOutputPowerManagerV1, ()> for Outputs {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrOutputPowerManagerV1,
+        _: <ZwlrOutputPowerManagerV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrOutputPowerV1, String> for Outputs {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputPowerV1,
+        event: zwlr_output_power_v1::Event,
+        name: &String,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_power_v1::Event::Mode { mode } => {
+                if let Some((_, on)) = state.power.get_mut(name) {
+                    *on = matches!(mode, WEnum::Value(zwlr_output_power_v1::Mode::On));
+                    state.changed = true;
+                }
+            }
+            zwlr_output_power_v1::Event::Failed => {
+                log::warn!("Power management for output {} is not available", name);
+                state.power.remove(name);
+                proxy.destroy();
+            }
+            _ => {}
+        }
+    }
+}
+
+// the wayland socket, polled by tokio
+struct WaylandFd(RawFd);
+impl AsRawFd for WaylandFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+async fn autodiscover(config: &Config, client: &MqttClient, outputs: &[String]) {
+    for output in outputs {
+        // dpms/power
+        let cmd_on = WlrCommand::OutputPowerOn {
+            output_name: output.clone(),
+        };
+        let cmd_off = WlrCommand::OutputPowerOff {
+            output_name: output.clone(),
+        };
+        let name = format!("{prefix}{output}_power", prefix = &config.wlr.name_prefix);
+        let unique_id = name.clone();
+        let switch = config.build_switch(
+            config.wlr.command_topic.clone(),
+            config.wlr.state_topic.clone(),
+            config.wlr.availability.clone(),
+            name,
+            unique_id,
+            format!(
+                "{{{{ '{on}' if (value_json.outputs['{output}']).power == true else '{off}' }}}}",
+                on = &config.switch_on_value,
+                off = &config.switch_off_value,
+            ),
+            format!("{{{{ value_json.outputs['{output}'] | tojson }}}}"),
+            serde_json::to_string(&cmd_on).unwrap(),
+            serde_json::to_string(&cmd_off).unwrap(),
+            config.wlr.state_topic.clone(),
+        );
+        config.publish_autodiscover(client, &switch).await;
+    }
+}
+
+// dispatches wayland events, publishes the output state and applies commands
+async fn wlr_state_task(
+    client: MqttClient,
+    config: &Config,
+    commands: &mut mpsc::Receiver<WlrCommand>,
+    mut connects: watch::Receiver<u32>,
+    mut ready: Ready,
+) -> anyhow::Result<()> {
+    let connection = Connection::connect_to_env()?;
+    let (globals, mut queue) = registry_queue_init::<Outputs>(&connection)?;
+    let qh = queue.handle();
+    let manager: ZwlrOutputManagerV1 = globals.bind(&qh, 1..=4, ())?;
+    let power_manager: Option<ZwlrOutputPowerManagerV1> = globals.bind(&qh, 1..=1, ()).ok();
+    if power_manager.is_none() {
+        log::warn!("Compositor does not support output power management");
+    }
+    let mut outputs = Outputs {
+        manager,
+        power_manager,
+        heads: HashMap::new(),
+        modes: HashMap::new(),
+        wl_outputs: HashMap::new(),
+        power: HashMap::new(),
+        serial: 0,
+        changed: false,
+    };
+    for global in globals.contents().clone_list() {
+        if global.interface == wl_output::WlOutput::interface().name {
+            let output =
+                globals
+                    .registry()
+                    .bind(global.name, global.version.min(4), &qh, global.name);
+            outputs.wl_outputs.insert(global.name, (output, None));
+        }
+    }
+    queue.roundtrip(&mut outputs)?;
+    ready.connected();
+
+    let fd = AsyncFd::new(WaylandFd(connection.as_fd().as_raw_fd()))?;
+    let mut discovered_outputs: Vec<String> = Vec::new();
+    loop {
+        if outputs.changed {
+            outputs.changed = false;
+            let state = outputs.state();
+            let mut names: Vec<String> = state.outputs.keys().cloned().collect();
+            names.sort();
+            if names != discovered_outputs {
+                log::debug!("Outputs changed, publishing autodiscover: {:?}", &names);
+                autodiscover(config, &client, &names).await;
+                discovered_outputs = names;
+            }
+            client
+                .publish(
+                    &config.wlr.state_topic,
+                    QoS::AtLeastOnce,
+                    false,
+                    serde_json::to_string(&state)?,
+                )
+                .await?;
+        }
+        queue.flush()?;
+        let Some(guard) = queue.prepare_read() else {
+            // there are already events queued
+            queue.dispatch_pending(&mut outputs)?;
+            continue;
+        };
+        tokio::select! {
+            readable = fd.readable() => {
+                let mut readable = readable?;
+                match guard.read() {
+                    Ok(_) => {}
+                    Err(wayland_client::backend::WaylandError::Io(e))
+                        if e.kind() == std::io::ErrorKind::WouldBlock =>
+                    {
+                        readable.clear_ready();
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                queue.dispatch_pending(&mut outputs)?;
+            }
+            command = commands.recv() => {
+                drop(guard);
+                let Some(command) = command else {
+                    return Ok(());
+                };
+                log::debug!("Running wlr command: {:?}", &command);
+                if let Err(e) = outputs.run_command(&qh, command) {
+                    log::error!("Error running wlr command: {:?}", e);
+                }
+            }
+            Ok(()) = connects.changed() => {
+                drop(guard);
+                // the broker might have lost the discovery configs and the state
+                discovered_outputs.clear();
+                outputs.changed = true;
+            }
+        }
+    }
+}
+
+pub struct WlrModule {
+    config: Config,
+    commands: mpsc::Sender<WlrCommand>,
+    /// locked by the state task, which owns the wayland connection
+    commands_receiver: Mutex<mpsc::Receiver<WlrCommand>>,
+}
+
+impl WlrModule {
+    pub fn new(config: Config) -> Self {
+        let (commands, commands_receiver) = mpsc::channel(16);
+        Self {
+            config,
+            commands,
+            commands_receiver: Mutex::new(commands_receiver),
+        }
+    }
+}
+
+#[async_trait]
+impl Module for WlrModule {
+    fn name(&self) -> &str {
+        "wlr"
+    }
+
+    fn availability(&self) -> &Availability {
+        &self.config.wlr.availability
+    }
+
+    fn command_topics(&self) -> Vec<String> {
+        vec![self.config.wlr.command_topic.clone()]
+    }
+
+    async fn discovery(&self, _client: &MqttClient) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn state(
+        &self,
+        client: MqttClient,
+        connects: watch::Receiver<u32>,
+        ready: Ready,
+    ) -> anyhow::Result<()> {
+        let mut commands = self.commands_receiver.lock().await;
+        wlr_state_task(client, &self.config, &mut commands, connects, ready).await
+    }
+
+    async fn command(
+        &self,
+        _client: &MqttClient,
+        _topic: &str,
+        payload: &str,
+    ) -> anyhow::Result<()> {
+        let wlr_command: WlrCommand = serde_json::from_str(payload)?;
+        // never wait here, commands would pile up while the wayland loop is stuck
+        match self.commands.try_send(wlr_command) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(command)) => {
+                log::error!(
+                    "Dropping wlr command, the state task is busy: {:?}",
+                    command
+                );
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(anyhow::anyhow!("wlr state task is not running")),
+        }
+    }
+}